@@ -1,129 +1,1531 @@
 use anyhow::{ensure, Context, Result};
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
 use once_cell::sync::Lazy;
 use rpassword::read_password;
-use std::io::{self, Write};
-use std::path::PathBuf;
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use crate::gus::GitUserSwitcher;
-use crate::shell::get_setup_script;
-use crate::user::User;
+use crate::config::{Config, SessionMode};
+use crate::doctor::{diagnose, scan_repos, Severity};
+use crate::error::{KeyError, UserError};
+use crate::gus::{get_coauthor_hook, get_coauthors, GitUserSwitcher, SwitchOptions, UserEdit};
+use crate::shell::{
+    get_rc_file_path, get_session_script_path, install_setup_line, list_session_scripts,
+    validate_setup_script, Shell,
+};
+use crate::sshconfig::read_ssh_config;
+use crate::sshkey::is_ssh_key_encrypted;
+use crate::timing;
+use crate::user::{User, Users};
 
 static DEFAULT_CONFIG_PATH: Lazy<PathBuf> =
     Lazy::new(|| dirs::home_dir().unwrap().join(".config/gus/config.toml"));
 
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// The exit statuses of a failed run, repeated as `code` in JSON errors.
+const ERROR_CODE: u8 = 1;
+const USAGE_ERROR_CODE: u8 = 2;
+const USER_NOT_FOUND_CODE: u8 = 3;
+const USER_EXISTS_CODE: u8 = 4;
+const KEY_MISSING_CODE: u8 = 5;
+const KEY_EXISTS_CODE: u8 = 6;
+const USER_AMBIGUOUS_CODE: u8 = 7;
+
 #[derive(Parser)]
 #[clap(name = env!("CARGO_PKG_NAME"), version = env!("CARGO_PKG_VERSION"), author = env!("CARGO_PKG_AUTHORS"), about = env!("CARGO_PKG_DESCRIPTION"))]
 struct Cli {
     #[clap(subcommand)]
     subcmd: Subcommands,
 
-    /// The path to the config file
+    /// The path to the config file ("-" reads it from stdin without writing anything)
     #[clap(long, short, default_value = &DEFAULT_CONFIG_PATH.to_str().unwrap())]
     config: PathBuf,
+
+    /// Use this users file instead of the one set in the config
+    #[clap(long, global = true)]
+    users_file: Option<PathBuf>,
+
+    /// Print how long each phase took to stderr
+    #[clap(long, global = true, hide = true)]
+    profile_timing: bool,
+
+    /// Print errors to stderr as JSON objects with a code and a message
+    #[clap(long, global = true)]
+    json: bool,
 }
 
 #[derive(Subcommand)]
 enum Subcommands {
     /// Echo a shell script to setup the shell for this app
-    Setup,
+    Setup {
+        /// Write the script to this file instead of echoing it
+        #[clap(long)]
+        output: Option<PathBuf>,
+
+        /// Check the script with the shell's syntax checker instead of echoing it
+        #[clap(long, conflicts_with = "output")]
+        validate: bool,
+
+        /// The shell to write the script for; detected from $SHELL when omitted
+        #[clap(long, value_enum)]
+        shell: Option<Shell>,
+
+        /// Add a line loading the script to the shell's rc file instead of echoing it
+        #[clap(long, conflicts_with_all = ["output", "validate"])]
+        install: bool,
+    },
 
     /// Add a new user
     Add {
         #[clap(flatten)]
-        user: User,
+        user: Box<User>,
+
+        /// Add the user even if the email domain is not allowed by the config
+        #[clap(long)]
+        force: bool,
+
+        /// Add the user even if another user already uses the same ssh key
+        #[clap(long)]
+        allow_shared_key: bool,
+
+        /// Read the passphrase of the new ssh key from the first line of stdin
+        #[clap(long)]
+        passphrase_stdin: bool,
+
+        /// Read the passphrase of the new ssh key from this file
+        #[clap(long, conflicts_with = "passphrase_stdin")]
+        passphrase_file: Option<PathBuf>,
+    },
+
+    /// Change the name, email or ssh key of a user
+    Edit {
+        /// The ID of the user to edit
+        #[clap(value_hint = ValueHint::Username)]
+        id: String,
+
+        #[clap(flatten)]
+        fields: UserEdit,
+
+        /// Change the email even if its domain is not allowed by the config
+        #[clap(long)]
+        force: bool,
+
+        /// Use the new ssh key even if another user already uses it
+        #[clap(long)]
+        allow_shared_key: bool,
     },
 
     /// Remove a user
     Remove {
         /// The ID of the user to remove
+        #[clap(value_hint = ValueHint::Username)]
         id: String,
     },
 
     /// Switch to a user
     Set {
-        /// The ID of the user to switch to
-        id: String,
+        /// The ID, email or name of the user to switch to (read from stdin when omitted)
+        #[clap(value_hint = ValueHint::Username)]
+        id: Option<String>,
+
+        #[clap(flatten)]
+        options: SwitchOptions,
+
+        /// Print the session script instead of switching to the user
+        #[clap(long)]
+        dump_script: bool,
+
+        /// Print the command sourcing the session script, for when the shell function isn't loaded
+        #[clap(long, conflicts_with = "dump_script")]
+        source: bool,
+
+        /// Run this command as the user after switching, exiting with its status
+        #[clap(long, num_args = 1.., allow_hyphen_values = true, value_name = "COMMAND", conflicts_with = "dump_script")]
+        then: Option<Vec<String>>,
+
+        /// Switch to a user that requires confirmation without asking
+        #[clap(long)]
+        confirm: bool,
+
+        /// Write the user into the current repository's git config instead of the shell
+        #[clap(long, conflicts_with_all = ["dump_script", "source", "then"])]
+        local: bool,
+
+        /// With --local, write to the current worktree's config (needs extensions.worktreeConfig)
+        #[clap(long, requires = "local")]
+        worktree: bool,
     },
 
+    /// Clear the current user and fall back to the git config
+    Unset,
+
     /// Show the current user
-    Current,
+    Current {
+        /// Echo the environment variables set for the current user as JSON
+        #[clap(long)]
+        json_env: bool,
+    },
 
     /// List all users
-    List,
+    List {
+        /// Echo a script starting a dedicated ssh-agent per user instead
+        #[clap(long)]
+        export_ssh_agents: bool,
+
+        /// Also show each user's ssh key path as stored in the users file
+        #[clap(long, conflicts_with = "export_ssh_agents")]
+        raw_paths: bool,
+
+        /// Also show each user's resolved ssh key path and whether it is passphrase-protected
+        #[clap(long, conflicts_with_all = ["export_ssh_agents", "raw_paths"])]
+        long: bool,
+    },
 
     /// Echo a public ssh key
     Key {
         /// The ID of the user to get the key for
+        #[clap(value_hint = ValueHint::Username)]
+        id: String,
+
+        /// Also load the private key into the running ssh-agent
+        #[clap(long)]
+        add_to_agent: bool,
+
+        /// Also check that the public key matches the private key
+        #[clap(long)]
+        verify_pair: bool,
+
+        /// Also check that the public key is registered to this GitHub account
+        #[clap(long, value_name = "ACCOUNT")]
+        verify_on_github: Option<String>,
+    },
+
+    /// Generate a new ssh key for a user, keeping the old one as a backup
+    RotateKey {
+        /// The ID of the user to rotate the key for
+        #[clap(value_hint = ValueHint::Username, required_unless_present = "all")]
+        id: Option<String>,
+
+        /// Rotate the keys of all users whose keys are managed by gus
+        #[clap(long, conflicts_with = "id")]
+        all: bool,
+
+        /// Read a single passphrase for all new keys from stdin
+        #[clap(long)]
+        passphrase_stdin: bool,
+    },
+
+    /// Generate a new ssh key for a user whose key is missing
+    RegenKey {
+        /// The ID of the user to generate the key for
+        #[clap(value_hint = ValueHint::Username)]
+        id: String,
+
+        /// Replace the key even if it already exists
+        #[clap(long)]
+        force: bool,
+    },
+
+    /// Change the ID of a user, keeping its ssh key
+    Rename {
+        /// The current ID of the user
+        #[clap(value_hint = ValueHint::Username)]
+        old_id: String,
+
+        /// The new ID of the user
+        new_id: String,
+    },
+
+    /// Rename the ssh key files of a user without changing the user ID
+    RenameKey {
+        /// The ID of the user whose key to rename
+        #[clap(value_hint = ValueHint::Username)]
+        id: String,
+
+        /// The new filename of the private key
+        new_filename: String,
+    },
+
+    /// Move the users file and the ssh key directory to another directory
+    RelocateData {
+        /// The directory to move the data to
+        new_dir: PathBuf,
+    },
+
+    /// Move the default-named ssh keys to another directory and make it the default
+    RelocateKeys {
+        /// The new default ssh key directory
+        #[clap(long)]
+        to: PathBuf,
+
+        /// Only show which files would be moved
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// Echo an ssh config block for the user's git host
+    SshConfig {
+        /// The ID of the user to get the ssh config for
+        #[clap(value_hint = ValueHint::Username)]
+        id: String,
+
+        /// Append the block to this ssh config file instead of echoing it
+        #[clap(long)]
+        append: Option<PathBuf>,
+    },
+
+    /// Manage session scripts of shells
+    Sessions {
+        #[clap(subcommand)]
+        subcmd: SessionsSubcommands,
+    },
+
+    /// Inspect the configuration
+    Config {
+        #[clap(subcommand)]
+        subcmd: ConfigSubcommands,
+    },
+
+    /// Check users and their ssh keys for problems
+    Doctor {
+        /// Also try to authenticate against each user's git host (slow, needs network)
+        #[clap(long)]
+        check_remote: bool,
+    },
+
+    /// Create users from existing ssh settings
+    Import {
+        /// Create a user for each Host with an IdentityFile in this ssh config (defaults to ~/.ssh/config)
+        #[clap(long, value_name = "PATH", required = true)]
+        from_ssh_config: Option<Option<PathBuf>>,
+    },
+
+    /// Echo every environment variable switching to the user would set
+    Env {
+        /// The ID, email or name of the user
+        #[clap(value_hint = ValueHint::Username)]
+        id: String,
+
+        /// The output format
+        #[clap(long, value_enum, default_value = "lines")]
+        format: EnvFormat,
+    },
+
+    /// Echo an .envrc snippet that switches to the user when direnv loads it
+    Direnv {
+        /// The ID, email or name of the user
+        #[clap(value_hint = ValueHint::Username)]
+        id: String,
+    },
+
+    /// Write the current user's name and email into git config so that git agrees with gus
+    Reconcile {
+        /// Write to the repository's config (default)
+        #[clap(long)]
+        local: bool,
+
+        /// Write to the global config instead
+        #[clap(long, conflicts_with = "local")]
+        global: bool,
+    },
+
+    /// Clone a repository as the user, using the user's preferred protocol
+    Clone {
+        /// The ID, email or name of the user
+        #[clap(value_hint = ValueHint::Username)]
         id: String,
+
+        /// The URL of the repository
+        url: String,
+
+        /// Extra arguments passed to git clone
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
+    /// Echo a commit trailer line for the user
+    Trailer {
+        /// The ID, email or name of the user (defaults to the current user)
+        #[clap(value_hint = ValueHint::Username)]
+        id: Option<String>,
+
+        /// The kind of trailer to echo
+        #[clap(long = "type", value_enum, default_value = "signed-off")]
+        trailer_type: TrailerType,
+    },
+
+    /// Check the users file for inconsistencies
+    UsersFile {
+        #[clap(subcommand)]
+        subcmd: UsersFileSubcommands,
+    },
+
+    /// Manage the co-authors added to commits made in this shell
+    Coauthor {
+        #[clap(subcommand)]
+        subcmd: CoauthorSubcommands,
+    },
+
+    /// Report which gus user each git repository under a directory commits as
+    Scan {
+        /// The directory to search for git repositories
+        root: PathBuf,
+
+        /// How many directory levels below the root to search
+        #[clap(long, default_value_t = 4)]
+        max_depth: usize,
+    },
+
+    /// Print completion candidates for the last of the given words
+    #[clap(name = "__complete", hide = true)]
+    Complete {
+        #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+        words: Vec<String>,
+    },
+
+    /// Test ssh authentication against the user's git host
+    TestConnection {
+        /// The ID of the user to test
+        #[clap(value_hint = ValueHint::Username)]
+        id: String,
+
+        /// The host to connect to (defaults to the user's host)
+        #[clap(long)]
+        host: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum CoauthorSubcommands {
+    /// Add a co-author to the commits of this shell
+    Add {
+        /// The ID, email or name of a user, or "Name <email>"
+        coauthor: String,
+    },
+
+    /// Remove a co-author from the commits of this shell
+    Remove {
+        /// The ID, email or name of a user, or "Name <email>"
+        coauthor: String,
+    },
+
+    /// List the co-authors of this shell
+    List,
+
+    /// Echo a prepare-commit-msg hook adding the co-authors as trailers
+    Hook,
+}
+
+#[derive(Subcommand)]
+enum UsersFileSubcommands {
+    /// Report users whose table key differs from their id field
+    Lint {
+        /// Rewrite the id fields to match the table keys
+        #[clap(long)]
+        fix: bool,
+
+        /// With --fix, rename the table keys to the id fields instead
+        #[clap(long, requires = "fix")]
+        use_inner_id: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum SessionsSubcommands {
+    /// List session scripts and whether their shells are alive
+    List,
+
+    /// Remove session scripts whose shells have exited
+    Prune,
+}
+
+#[derive(Subcommand)]
+enum ConfigSubcommands {
+    /// Show the settings that differ from the defaults
+    Diff,
+
+    /// Echo the JSON schema of the config or users file
+    Schema {
+        /// The file to get the schema for
+        #[clap(value_enum, default_value = "config")]
+        target: SchemaTarget,
     },
 }
 
+#[derive(Clone, ValueEnum)]
+enum SchemaTarget {
+    Config,
+    Users,
+}
+
+#[derive(Clone, ValueEnum)]
+enum EnvFormat {
+    Lines,
+    Json,
+}
+
+#[derive(Clone, ValueEnum)]
+enum TrailerType {
+    SignedOff,
+    CoAuthored,
+}
+
 pub fn run() -> Result<()> {
-    let cli = Cli::parse();
+    #[cfg(feature = "keychain")]
+    if let Ok(id) = std::env::var(crate::sshkey::ASKPASS_USER_ID_KEY) {
+        println!("{}", crate::keychain::get_passphrase(&id)?);
+        return Ok(());
+    }
 
-    let mut gus = GitUserSwitcher::from(&cli.config);
+    let cli = match Cli::try_parse() {
+        Ok(cli) => cli,
+        // usage errors are reported like any other error once --json was asked for
+        Err(err) if err.use_stderr() && std::env::args().any(|arg| arg == "--json") => {
+            JSON_ERRORS.store(true, Ordering::Relaxed);
+            return Err(err.into());
+        }
+        Err(err) => err.exit(),
+    };
+    if cli.profile_timing {
+        timing::enable();
+    }
+    JSON_ERRORS.store(cli.json, Ordering::Relaxed);
+
+    // completion must not fail on a broken config, so it runs before the config is loaded
+    if let Subcommands::Complete { words } = &cli.subcmd {
+        for candidate in get_completions(&cli, words) {
+            println!("{}", candidate);
+        }
+        return Ok(());
+    }
+
+    let is_config_stdin = cli.config.as_os_str() == "-";
+    let users_file = cli.users_file.as_deref();
+    let mut gus = if is_config_stdin {
+        GitUserSwitcher::from_reader(io::stdin(), users_file)?
+    } else {
+        GitUserSwitcher::open(&cli.config, users_file)?
+    };
 
     match cli.subcmd {
-        Subcommands::Setup => {
-            println!("{}", gus.get_setup_script())
+        Subcommands::Setup {
+            output,
+            validate,
+            shell,
+            install,
+        } => {
+            let shell = shell.unwrap_or_else(Shell::detect);
+            if install {
+                let rc_path = get_rc_file_path(shell);
+                if install_setup_line(shell, &rc_path)? {
+                    println!("added gus setup to {}", rc_path.display());
+                    println!("restart your shell or source the file to load it");
+                } else {
+                    println!("gus setup is already installed in {}", rc_path.display());
+                }
+                return Ok(());
+            }
+            let script = gus.get_setup_script(shell);
+            if validate {
+                validate_setup_script(shell, &script)?;
+                println!("setup script is valid");
+            } else if let Some(path) = output {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).with_context(|| {
+                        format!("failed to create directory: {}", parent.display())
+                    })?;
+                }
+                std::fs::write(&path, &script)
+                    .with_context(|| format!("failed to write setup script: {}", path.display()))?;
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))
+                    .with_context(|| format!("failed to set permissions of: {}", path.display()))?;
+                let path = std::path::absolute(&path).unwrap_or(path);
+                println!("wrote setup script to {}", path.display());
+                println!("add the following line to your shell rc file:");
+                println!("  source \"{}\"", path.display());
+            } else {
+                println!("{}", script)
+            }
         }
-        Subcommands::Add { user } => {
+        Subcommands::Add {
+            user,
+            force,
+            allow_shared_key,
+            passphrase_stdin,
+            passphrase_file,
+        } => {
             ensure!(
                 !gus.exists_user(&user.id),
-                "user with id '{}' already exists",
-                user.id
+                UserError::AlreadyExists(user.id.clone())
             );
 
-            let is_required_sshkey_passphrase = if let Some(sshkey_path) = &user.sshkey_path {
+            // fail before prompting; forced warnings are printed once by add_user
+            if !force {
+                gus.validate_email_domain(&user.email)?;
+            }
+            if !allow_shared_key {
+                gus.check_shared_sshkey(&user, false)?;
+            }
+
+            let is_required_sshkey_passphrase = if user.agent_backed {
+                false
+            } else if user.passphrase_keychain {
+                true
+            } else if let Some(sshkey_path) = user.primary_key() {
                 !sshkey_path.exists()
             } else {
                 true
             };
 
-            let sshkey_passphrase = if is_required_sshkey_passphrase {
-                let msg_suffix = if gus.config.min_sshkey_passphrase_length > 0 {
-                    format!(
-                        "(at least {} chars required)",
-                        gus.config.min_sshkey_passphrase_length
-                    )
-                } else {
-                    "(10+ chars recommended)".to_string()
-                };
-                print!("Enter new ssh key passphrase {}: ", msg_suffix);
-                io::stdout().flush().unwrap();
-                let pass = read_password().context("failed to read ssh key passphrase")?;
-                ensure!(
-                    pass.len() >= gus.config.min_sshkey_passphrase_length,
-                    "ssh key passphrase must be at least {} characters",
-                    gus.config.min_sshkey_passphrase_length
-                );
-                Some(pass)
-            } else {
+            let sshkey_passphrase = if !is_required_sshkey_passphrase {
                 None
+            } else if passphrase_stdin {
+                Some(read_passphrase_from_stdin()?)
+            } else if let Some(path) = passphrase_file {
+                let pass = std::fs::read_to_string(&path).with_context(|| {
+                    format!("failed to read ssh key passphrase file: {}", path.display())
+                })?;
+                Some(pass.trim().to_string())
+            } else {
+                Some(read_new_sshkey_passphrase(&gus, None)?)
             };
 
-            gus.add_user(user, sshkey_passphrase.as_deref())?;
+            gus.add_user(*user, sshkey_passphrase.as_deref(), force, allow_shared_key)?;
+        }
+        Subcommands::Edit {
+            id,
+            fields,
+            force,
+            allow_shared_key,
+        } => {
+            gus.edit_user(&id, fields, force, allow_shared_key)?;
         }
         Subcommands::Remove { id } => {
             gus.remove_user(&id)?;
         }
-        Subcommands::Set { id } => {
-            gus.switch_user(&id)?;
+        Subcommands::Set {
+            id,
+            options,
+            dump_script,
+            source,
+            then,
+            confirm,
+            local,
+            worktree,
+        } => {
+            let id = match id {
+                Some(id) => id,
+                None => read_id_from_stdin()?,
+            };
+            let user = gus.find_user(&id)?;
+            if user.require_confirm && !confirm && !dump_script {
+                ensure!(
+                    io::stdin().is_terminal(),
+                    "user '{}' requires confirmation (use --confirm)",
+                    user.id
+                );
+                confirm_switch(&user.id, &mut io::stdin().lock())?;
+            }
+            if local {
+                let scope = if worktree { "worktree" } else { "local" };
+                for key in gus.switch_user_local(&id, Path::new("."), scope)? {
+                    eprintln!("set {} in the {} git config", key, scope);
+                }
+            } else if dump_script {
+                print!("{}", gus.get_session_script(&id, &options)?);
+            } else if let Some(command) = then {
+                // in eval mode the shell would eval the command's output along with the exports
+                ensure!(
+                    gus.config.session_mode == SessionMode::File,
+                    "--then is not available with session_mode = \"Eval\""
+                );
+                gus.switch_user(&id, &options)?;
+                let status = gus.run_as_user(&id, &options, &command)?;
+                std::process::exit(status.code().unwrap_or(1));
+            } else {
+                ensure!(
+                    !source || gus.config.session_mode == SessionMode::File,
+                    "--source is not available with session_mode = \"Eval\""
+                );
+                gus.switch_user(&id, &options)?;
+                if source {
+                    println!(
+                        "source \"{}\"",
+                        get_session_script_path(options.temp_dir.as_deref()).display()
+                    );
+                }
+            }
+        }
+        Subcommands::Unset => {
+            gus.unset_user()?;
         }
-        Subcommands::Current => {
-            println!("{}", gus.get_current_user().context("no current user")?);
+        Subcommands::Current { json_env } => {
+            if json_env {
+                let env = gus
+                    .get_current_user()
+                    .map(|user| gus.build_env(user, &SwitchOptions::default()))
+                    .transpose()?
+                    .unwrap_or_default();
+                println!("{}", env_to_json(env));
+            } else {
+                println!("{}", gus.get_current_user().context("no current user")?);
+            }
         }
-        Subcommands::List => {
-            for user in gus.list_users() {
-                println!("{}", user);
+        Subcommands::List {
+            export_ssh_agents,
+            raw_paths,
+            long,
+        } => {
+            if export_ssh_agents {
+                let mut users = gus.list_users();
+                users.sort_by(|a, b| a.id.cmp(&b.id));
+                for script in users
+                    .into_iter()
+                    .filter_map(|user| gus.get_ssh_agent_script(user))
+                {
+                    print!("{}", script);
+                }
+            } else if raw_paths {
+                for user in gus.list_users() {
+                    println!("{}\t{}", user, get_raw_sshkey_path(user));
+                }
+            } else if long {
+                for user in gus.list_users() {
+                    if user.agent_backed {
+                        println!("{}", user);
+                        continue;
+                    }
+                    let path = user.get_sshkey_path(&gus.config.default_sshkey_dir);
+                    let status = if !path.exists() {
+                        "missing"
+                    } else {
+                        match is_ssh_key_encrypted(&path) {
+                            Ok(true) => "encrypted",
+                            Ok(false) => "unencrypted",
+                            Err(_) => "unknown",
+                        }
+                    };
+                    println!("{}\t{}\t{}", user, path.display(), status);
+                }
+            } else {
+                for user in gus.list_users() {
+                    println!("{}", user);
+                }
             }
         }
-        Subcommands::Key { id } => {
+        Subcommands::Key {
+            id,
+            add_to_agent,
+            verify_pair,
+            verify_on_github,
+        } => {
             let pubkey = gus.get_public_sshkey(&id)?;
             print!("{}", pubkey);
+            if verify_pair {
+                ensure!(
+                    gus.verify_sshkey_pair(&id)?,
+                    "public key does not match the private key for user: {}",
+                    id
+                );
+                eprintln!("ssh key pair matches for user: {}", id);
+            }
+            if let Some(account) = verify_on_github {
+                ensure!(
+                    gus.is_sshkey_on_github(&id, &account)?,
+                    "ssh key of user '{}' is not registered to GitHub account: {}",
+                    id,
+                    account
+                );
+                eprintln!(
+                    "ssh key of user '{}' is registered to GitHub account: {}",
+                    id, account
+                );
+            }
+            if add_to_agent {
+                gus.add_sshkey_to_agent(&id)?;
+                eprintln!("added ssh key to agent for user: {}", id);
+            }
+        }
+        Subcommands::RotateKey {
+            id,
+            all,
+            passphrase_stdin,
+        } => {
+            let shared_passphrase = if passphrase_stdin {
+                Some(read_passphrase_from_stdin()?)
+            } else {
+                None
+            };
+
+            if !all {
+                let id = id.unwrap();
+                let pass = match shared_passphrase {
+                    Some(pass) => pass,
+                    None => read_new_sshkey_passphrase(&gus, Some(&id))?,
+                };
+                print!("{}", gus.rotate_sshkey(&id, &pass)?);
+                return Ok(());
+            }
+
+            let mut users = gus.list_users();
+            users.sort_by(|a, b| a.id.cmp(&b.id));
+
+            // each key is printed as soon as it is rotated, so a later failure can't hide it
+            let mut rotated = 0;
+            let mut skipped = 0;
+            for user in users {
+                if user.agent_backed || user.primary_key().is_some() {
+                    eprintln!(
+                        "warning: skipping user '{}' whose ssh key is not managed by gus",
+                        user.id
+                    );
+                    skipped += 1;
+                    continue;
+                }
+                let result = match &shared_passphrase {
+                    Some(pass) => Ok(pass.clone()),
+                    None => read_new_sshkey_passphrase(&gus, Some(&user.id)),
+                }
+                .and_then(|pass| gus.rotate_sshkey(&user.id, &pass));
+                let pubkey = match result {
+                    Ok(pubkey) => pubkey,
+                    Err(err) => {
+                        eprintln!(
+                            "rotated {} key(s), skipped {} before failing on user '{}'",
+                            rotated, skipped, user.id
+                        );
+                        return Err(err);
+                    }
+                };
+                println!("# {}", user.id);
+                print!("{}", pubkey);
+                io::stdout().flush().unwrap();
+                rotated += 1;
+            }
+
+            eprintln!("rotated {} key(s), skipped {}", rotated, skipped);
+        }
+        Subcommands::RegenKey { id, force } => {
+            gus.check_regenerate_sshkey(&id, force)?;
+            let pass = read_new_sshkey_passphrase(&gus, Some(&id))?;
+            print!("{}", gus.regenerate_sshkey(&id, &pass, force)?);
+        }
+        Subcommands::Rename { old_id, new_id } => {
+            gus.rename_user(&old_id, &new_id)?;
+        }
+        Subcommands::RenameKey { id, new_filename } => {
+            gus.rename_sshkey(&id, &new_filename)?;
+        }
+        Subcommands::RelocateData { new_dir } => {
+            gus.relocate_data(&new_dir)?;
+            println!("moved data to {}", new_dir.display());
+        }
+        Subcommands::RelocateKeys { to, dry_run } => {
+            for (from, to) in gus.relocate_sshkeys(&to, dry_run)? {
+                println!("{} -> {}", from.display(), to.display());
+            }
+        }
+        Subcommands::SshConfig { id, append } => {
+            let block = gus.get_ssh_config(&id)?;
+            if let Some(path) = append {
+                if let Some(parent) = path.parent() {
+                    std::fs::create_dir_all(parent).with_context(|| {
+                        format!(
+                            "failed to create ssh config directory: {}",
+                            parent.display()
+                        )
+                    })?;
+                }
+                let mut file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .with_context(|| format!("failed to open ssh config: {}", path.display()))?;
+                write!(file, "\n{}", block)
+                    .with_context(|| format!("failed to write ssh config: {}", path.display()))?;
+                eprintln!(
+                    "appended ssh config for user '{}' to {}",
+                    id,
+                    path.display()
+                );
+            } else {
+                print!("{}", block);
+            }
+        }
+        Subcommands::Sessions { subcmd } => match subcmd {
+            SessionsSubcommands::List => {
+                for script in list_session_scripts()? {
+                    let state = if script.is_alive { "alive" } else { "dead" };
+                    println!("{}\t{}\t{}", script.pid, state, script.path.display());
+                }
+            }
+            SessionsSubcommands::Prune => {
+                let mut count = 0;
+                let mut reclaimed = 0;
+                for script in list_session_scripts()? {
+                    if script.is_alive {
+                        continue;
+                    }
+                    std::fs::remove_file(&script.path).with_context(|| {
+                        format!("failed to remove session script: {}", script.path.display())
+                    })?;
+                    count += 1;
+                    reclaimed += script.size;
+                }
+                println!(
+                    "removed {} session script(s), reclaimed {} bytes",
+                    count, reclaimed
+                );
+            }
+        },
+        Subcommands::Config { subcmd } => match subcmd {
+            ConfigSubcommands::Diff => {
+                ensure!(
+                    !is_config_stdin,
+                    "config diff requires a config file, not stdin"
+                );
+                let config = Config::open(&cli.config)?;
+                for diff in config.diff_from_default(&cli.config)? {
+                    println!("{}", diff);
+                }
+            }
+            ConfigSubcommands::Schema { target } => {
+                println!("{}", get_schema(target)?);
+            }
+        },
+        Subcommands::Doctor { check_remote } => {
+            let diagnoses = diagnose(&gus, check_remote);
+            for diagnosis in &diagnoses {
+                println!("{}", diagnosis);
+            }
+            let errors = diagnoses
+                .iter()
+                .filter(|diagnosis| diagnosis.severity == Severity::Error)
+                .count();
+            ensure!(errors == 0, "doctor found {} problem(s)", errors);
+        }
+        Subcommands::Import { from_ssh_config } => {
+            let path = from_ssh_config
+                .flatten()
+                .unwrap_or_else(|| dirs::home_dir().unwrap().join(".ssh/config"));
+            for host in read_ssh_config(&path)? {
+                let Some(identity_file) = host.identity_file else {
+                    continue;
+                };
+                if gus.exists_user(&host.alias) {
+                    eprintln!("warning: user '{}' already exists, skipped", host.alias);
+                    continue;
+                }
+                if !identity_file.exists() {
+                    eprintln!(
+                        "warning: ssh key for '{}' does not exist, skipped: {}",
+                        host.alias,
+                        identity_file.display()
+                    );
+                    continue;
+                }
+
+                let mut user = User {
+                    id: host.alias,
+                    sshkey_path: Some(identity_file),
+                    host: host.hostname,
+                    ..Default::default()
+                };
+                if let Some(other) = gus.find_user_sharing_sshkey(&user) {
+                    eprintln!(
+                        "warning: ssh key for '{}' is already used by user '{}', skipped",
+                        user.id, other.id
+                    );
+                    continue;
+                }
+
+                println!(
+                    "Importing {} ({})",
+                    user.id,
+                    user.primary_key().unwrap().display()
+                );
+                user.name = read_line("  name: ")?;
+                user.email = read_line("  email: ")?;
+                if user.name.is_empty() || user.email.is_empty() {
+                    eprintln!("warning: name or email is empty, skipped: {}", user.id);
+                    continue;
+                }
+                if let Err(err) = gus.validate_email_domain(&user.email) {
+                    eprintln!("warning: {}, skipped: {}", err, user.id);
+                    continue;
+                }
+
+                gus.add_user(user, None, false, false)?;
+            }
+        }
+        Subcommands::Scan { root, max_depth } => {
+            for diagnosis in scan_repos(&gus, &root, max_depth)? {
+                println!("{}", diagnosis);
+            }
+        }
+        Subcommands::Env { id, format } => {
+            let user = gus.find_user(&id)?;
+            let env = gus.build_env(user, &SwitchOptions::default())?;
+            println!("{}", format_env(env, &format));
+        }
+        Subcommands::Direnv { id } => {
+            print!("{}", gus.get_direnv_script(&id)?);
+        }
+        Subcommands::Reconcile { local: _, global } => {
+            let scope = if global { "global" } else { "local" };
+            let user = gus.get_current_user().context("no current user")?;
+            let changes = gus.reconcile_git_config(user, Path::new("."), scope)?;
+            if changes.is_empty() {
+                println!("{} git config already matches the current user", scope);
+            }
+            for (key, old, new) in changes {
+                println!(
+                    "{}: {} -> {}",
+                    key,
+                    old.as_deref().unwrap_or("(unset)"),
+                    new
+                );
+            }
+        }
+        Subcommands::Clone { id, url, args } => {
+            let status = gus.clone_repo(&id, &url, &args)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Subcommands::Trailer { id, trailer_type } => {
+            let user = match &id {
+                Some(id) => gus.find_user(id)?,
+                None => gus.get_current_user().context("no current user")?,
+            };
+            let key = match trailer_type {
+                TrailerType::SignedOff => "Signed-off-by",
+                TrailerType::CoAuthored => "Co-authored-by",
+            };
+            println!("{}: {} <{}>", key, user.name, user.email);
+        }
+        Subcommands::UsersFile { subcmd } => match subcmd {
+            UsersFileSubcommands::Lint { fix, use_inner_id } => {
+                if fix {
+                    for (key, id) in gus.repair_users_file(use_inner_id)? {
+                        if use_inner_id {
+                            println!("renamed user '{}' to its id '{}'", key, id);
+                        } else {
+                            println!("changed the id of user '{}' from '{}'", key, id);
+                        }
+                    }
+                } else {
+                    let mismatches = gus.users.find_id_mismatches();
+                    for (key, id) in &mismatches {
+                        println!("user '{}' has a different id: '{}'", key, id);
+                    }
+                    ensure!(
+                        mismatches.is_empty(),
+                        "{} user(s) have mismatched ids (use --fix to repair them)",
+                        mismatches.len()
+                    );
+                }
+            }
+        },
+        Subcommands::Coauthor { subcmd } => match subcmd {
+            CoauthorSubcommands::Add { coauthor } => gus.add_coauthor(&coauthor)?,
+            CoauthorSubcommands::Remove { coauthor } => gus.remove_coauthor(&coauthor)?,
+            CoauthorSubcommands::List => {
+                for coauthor in get_coauthors() {
+                    println!("{}", coauthor);
+                }
+            }
+            CoauthorSubcommands::Hook => print!("{}", get_coauthor_hook()),
+        },
+        Subcommands::Complete { .. } => unreachable!(),
+        Subcommands::TestConnection { id, host } => {
+            let (is_authenticated, message) = gus.test_connection(&id, host.as_deref(), false)?;
+            if !message.is_empty() {
+                println!("{}", message);
+            }
+            ensure!(
+                is_authenticated,
+                "ssh authentication failed for user: {}",
+                id
+            );
+            println!("ssh authentication succeeded for user: {}", id);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "schema")]
+fn get_schema(target: SchemaTarget) -> Result<String> {
+    let schema = match target {
+        SchemaTarget::Config => schemars::schema_for!(crate::config::Config),
+        // the users file is a table of users keyed by their IDs
+        SchemaTarget::Users => {
+            let mut schema =
+                schemars::schema_for!(std::collections::HashMap<String, crate::user::User>);
+            schema.schema.metadata().title = Some("Users".to_string());
+            schema
+        }
+    };
+    serde_json::to_string_pretty(&schema).context("failed to serialize schema")
+}
+
+#[cfg(not(feature = "schema"))]
+fn get_schema(_target: SchemaTarget) -> Result<String> {
+    anyhow::bail!("gus was built without schema support (enable the 'schema' feature)")
+}
+
+fn get_completions(cli: &Cli, words: &[String]) -> Vec<String> {
+    let current = words.last().map(String::as_str).unwrap_or("");
+    let mut candidates = match words.len() {
+        0 | 1 => Cli::command()
+            .get_subcommands()
+            .filter(|subcmd| !subcmd.is_hide_set())
+            .map(|subcmd| subcmd.get_name().to_string())
+            .collect(),
+        2 if takes_user_first(&words[0]) => get_user_ids(cli),
+        _ => Vec::new(),
+    };
+    candidates.retain(|candidate| candidate.starts_with(current));
+    candidates.sort();
+    candidates
+}
+
+/// Whether the first positional argument of `subcmd` is marked as a user with `ValueHint::Username`.
+fn takes_user_first(subcmd: &str) -> bool {
+    Cli::command()
+        .find_subcommand(subcmd)
+        .and_then(|subcmd| subcmd.get_positionals().next())
+        .is_some_and(|arg| arg.get_value_hint() == ValueHint::Username)
+}
+
+fn get_user_ids(cli: &Cli) -> Vec<String> {
+    if cli.config.as_os_str() == "-" || !cli.config.exists() {
+        return Vec::new();
+    }
+    let users_file = match &cli.users_file {
+        Some(path) => path.clone(),
+        None => match Config::open(&cli.config) {
+            Ok(config) => config.users_file_path,
+            Err(_) => return Vec::new(),
+        },
+    };
+    match Users::open(&users_file) {
+        Ok(users) => users.list().iter().map(|user| user.id.clone()).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Prints the error of a failed run to stderr, as JSON when `--json` was given.
+/// Returns the exit status to fail with.
+pub fn report_error(err: &anyhow::Error) -> u8 {
+    let code = get_error_code(err);
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        eprintln!("{}", format_json_error(err, code));
+    } else {
+        eprintln!("Error: {:?}", err);
+    }
+    code
+}
+
+/// Picks the exit status from the first `UserError`, `KeyError` or clap error in the chain.
+fn get_error_code(err: &anyhow::Error) -> u8 {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<UserError>() {
+            return match err {
+                UserError::NotFound(_) | UserError::NoMatch(_) => USER_NOT_FOUND_CODE,
+                UserError::AlreadyExists(_) => USER_EXISTS_CODE,
+                UserError::Ambiguous { .. } => USER_AMBIGUOUS_CODE,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<KeyError>() {
+            return match err {
+                KeyError::Missing(_) => KEY_MISSING_CODE,
+                KeyError::AlreadyExists(_) => KEY_EXISTS_CODE,
+            };
+        }
+        if cause.is::<clap::Error>() {
+            return USAGE_ERROR_CODE;
         }
     }
+    ERROR_CODE
+}
 
+fn format_json_error(err: &anyhow::Error, code: u8) -> String {
+    // clap renders usage and a hint after the message, which only make sense on a terminal
+    let message = match err.downcast_ref::<clap::Error>() {
+        Some(err) => err
+            .to_string()
+            .lines()
+            .next()
+            .unwrap_or_default()
+            .trim_start_matches("error: ")
+            .to_string(),
+        None => err.to_string(),
+    };
+    let causes = err
+        .chain()
+        .skip(1)
+        .map(|cause| serde_json::Value::String(cause.to_string()))
+        .collect();
+    let error = serde_json::Value::Object(
+        [
+            ("code".to_string(), serde_json::Value::from(code)),
+            ("message".to_string(), serde_json::Value::String(message)),
+            ("causes".to_string(), serde_json::Value::Array(causes)),
+        ]
+        .into_iter()
+        .collect(),
+    );
+    error.to_string()
+}
+
+/// The ssh key path as stored in the users file, without resolving `~` or the default directory.
+fn get_raw_sshkey_path(user: &User) -> String {
+    match user.primary_key() {
+        Some(path) => path.display().to_string(),
+        None if user.agent_backed => "(none)".to_string(),
+        None => format!("(default) {}", user.get_sshkey_name()),
+    }
+}
+
+fn env_to_json(env: Vec<(String, String)>) -> serde_json::Value {
+    serde_json::Value::Object(
+        env.into_iter()
+            .map(|(key, value)| (key, serde_json::Value::String(value)))
+            .collect(),
+    )
+}
+
+fn format_env(env: Vec<(String, String)>, format: &EnvFormat) -> String {
+    match format {
+        EnvFormat::Lines => env
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        EnvFormat::Json => env_to_json(env).to_string(),
+    }
+}
+
+fn read_id_from_stdin() -> Result<String> {
+    ensure!(
+        !io::stdin().is_terminal(),
+        "no user given (pass an id or pipe one to stdin)"
+    );
+    read_id(&mut io::stdin().lock())
+}
+
+fn read_id(reader: &mut impl BufRead) -> Result<String> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .context("failed to read user id from stdin")?;
+    let id = line.trim().to_string();
+    ensure!(!id.is_empty(), "no user id was read from stdin");
+    Ok(id)
+}
+
+fn confirm_switch(id: &str, reader: &mut impl BufRead) -> Result<()> {
+    // stdout may be eval'ed by the shell function, so the prompt goes to stderr
+    eprint!("Switch to user '{}'? [y/N]: ", id);
+    let mut answer = String::new();
+    reader
+        .read_line(&mut answer)
+        .context("failed to read from stdin")?;
+    ensure!(
+        matches!(answer.trim(), "y" | "Y" | "yes"),
+        "switch to user '{}' was not confirmed",
+        id
+    );
     Ok(())
 }
+
+fn read_line(prompt: &str) -> Result<String> {
+    print!("{}", prompt);
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .context("failed to read from stdin")?;
+    Ok(line.trim().to_string())
+}
+
+fn read_passphrase_from_stdin() -> Result<String> {
+    let mut line = String::new();
+    io::stdin()
+        .read_line(&mut line)
+        .context("failed to read ssh key passphrase from stdin")?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn read_new_sshkey_passphrase(gus: &GitUserSwitcher, id: Option<&str>) -> Result<String> {
+    let msg_suffix = if gus.config.min_sshkey_passphrase_length > 0 {
+        format!(
+            "(at least {} chars required)",
+            gus.config.min_sshkey_passphrase_length
+        )
+    } else {
+        "(10+ chars recommended)".to_string()
+    };
+    match id {
+        Some(id) => print!("Enter new ssh key passphrase for {} {}: ", id, msg_suffix),
+        None => print!("Enter new ssh key passphrase {}: ", msg_suffix),
+    }
+    io::stdout().flush().unwrap();
+    let pass = read_password().context("failed to read ssh key passphrase")?;
+    ensure!(
+        pass.len() >= gus.config.min_sshkey_passphrase_length,
+        "ssh key passphrase must be at least {} characters",
+        gus.config.min_sshkey_passphrase_length
+    );
+    Ok(pass)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn complete(config: &Path, words: &[&str]) -> Vec<String> {
+        let mut args = vec!["gus", "--config", config.to_str().unwrap(), "__complete"];
+        args.extend(words);
+        let cli = Cli::try_parse_from(args).unwrap();
+        let Subcommands::Complete { words } = &cli.subcmd else {
+            unreachable!()
+        };
+        get_completions(&cli, words)
+    }
+
+    #[test]
+    fn complete_lists_user_ids_for_user_arguments() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let config = Config {
+            users_file_path: dir.path().join("users.toml"),
+            ..Config::default()
+        };
+        config.save(&config_path).unwrap();
+        let mut users = Users::new();
+        for id in ["work", "home", "oss"] {
+            users
+                .add(User {
+                    id: id.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        users.save(&config.users_file_path).unwrap();
+
+        assert_eq!(
+            complete(&config_path, &["set", ""]),
+            ["home", "oss", "work"]
+        );
+        assert_eq!(complete(&config_path, &["rename", "w"]), ["work"]);
+        assert_eq!(
+            complete(&config_path, &["direnv", ""]),
+            ["home", "oss", "work"]
+        );
+        assert!(complete(&config_path, &["scan", ""]).is_empty());
+        assert!(complete(&config_path, &["set", "work", ""]).is_empty());
+        assert_eq!(complete(&config_path, &["rena"]), ["rename", "rename-key"]);
+    }
+
+    fn parse_json_error(err: &anyhow::Error) -> serde_json::Value {
+        serde_json::from_str(&format_json_error(err, get_error_code(err))).unwrap()
+    }
+
+    #[test]
+    fn json_errors_carry_distinct_codes() {
+        let err = anyhow::Error::new(UserError::NotFound("work".to_string()));
+        let json = parse_json_error(&err);
+        assert_eq!(json["code"], 3);
+        assert_eq!(json["message"], "user with id 'work' does not exist");
+        assert_eq!(json["causes"], serde_json::json!([]));
+
+        let err = anyhow::Error::new(KeyError::Missing(PathBuf::from("/keys/id_work")))
+            .context("failed to switch");
+        let json = parse_json_error(&err);
+        assert_eq!(json["code"], 5);
+        assert_eq!(json["message"], "failed to switch");
+        assert_eq!(
+            json["causes"],
+            serde_json::json!(["ssh key does not exist: /keys/id_work"])
+        );
+
+        let err = anyhow::Error::new(UserError::AlreadyExists("work".to_string()));
+        assert_eq!(parse_json_error(&err)["code"], 4);
+        assert_eq!(parse_json_error(&anyhow::anyhow!("boom"))["code"], 1);
+    }
+
+    #[test]
+    fn usage_errors_are_json_errors() {
+        let err: anyhow::Error = Cli::try_parse_from(["gus", "--json", "set", "--bogus"])
+            .err()
+            .unwrap()
+            .into();
+        let json = parse_json_error(&err);
+        assert_eq!(json["code"], 2);
+        assert_eq!(json["message"], "unexpected argument '--bogus' found");
+    }
+
+    #[test]
+    fn confirm_switch_accepts_only_yes() {
+        for answer in ["y\n", "Y\n", "yes\n", "  yes  \n"] {
+            assert!(confirm_switch("alice", &mut answer.as_bytes()).is_ok());
+        }
+        for answer in ["", "\n", "n\n", "no\n", "yep\n"] {
+            let err = confirm_switch("alice", &mut answer.as_bytes()).unwrap_err();
+            assert_eq!(err.to_string(), "switch to user 'alice' was not confirmed");
+        }
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn schemas_describe_the_config_and_users_files() {
+        let config: serde_json::Value =
+            serde_json::from_str(&get_schema(SchemaTarget::Config).unwrap()).unwrap();
+        assert_eq!(config["title"], "Config");
+        assert!(config["properties"]["default_sshkey_dir"].is_object());
+        assert!(config["properties"]["session_mode"].is_object());
+
+        let users: serde_json::Value =
+            serde_json::from_str(&get_schema(SchemaTarget::Users).unwrap()).unwrap();
+        assert_eq!(users["title"], "Users");
+        assert_eq!(users["type"], "object");
+        assert_eq!(users["additionalProperties"]["$ref"], "#/definitions/User");
+        assert!(users["definitions"]["User"]["properties"]["email"].is_object());
+    }
+
+    #[cfg(not(feature = "schema"))]
+    #[test]
+    fn schema_needs_the_schema_feature() {
+        let err = get_schema(SchemaTarget::Config).unwrap_err();
+        assert!(err.to_string().contains("enable the 'schema' feature"));
+    }
+
+    #[test]
+    fn json_env_has_every_session_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        Config {
+            users_file_path: dir.path().join("users.toml"),
+            default_sshkey_dir: dir.path().join("sshkeys"),
+            ..Config::default()
+        }
+        .save(&config_path)
+        .unwrap();
+        let gus = GitUserSwitcher::open(&config_path, None).unwrap();
+        let user = User {
+            id: "work".to_string(),
+            name: "Jane Doe".to_string(),
+            email: "jane@corp.example".to_string(),
+            sign_format: Some(crate::user::SignFormat::Ssh),
+            ..Default::default()
+        };
+
+        let json = env_to_json(gus.build_env(&user, &SwitchOptions::default()).unwrap());
+        let env = json.as_object().unwrap();
+        assert_eq!(env["GUS_USER_ID"], "work");
+        assert_eq!(env["GIT_AUTHOR_NAME"], "Jane Doe");
+        assert_eq!(env["GIT_AUTHOR_EMAIL"], "jane@corp.example");
+        assert_eq!(env["GIT_COMMITTER_NAME"], "Jane Doe");
+        assert_eq!(env["GIT_COMMITTER_EMAIL"], "jane@corp.example");
+        assert!(env["GIT_SSH_COMMAND"]
+            .as_str()
+            .unwrap()
+            .starts_with("ssh -i "));
+
+        let count: usize = env["GIT_CONFIG_COUNT"].as_str().unwrap().parse().unwrap();
+        assert_eq!(env["GUS_GIT_CONFIG_COUNT"], count.to_string());
+        for i in 0..count {
+            assert!(env[&format!("GIT_CONFIG_KEY_{}", i)].is_string());
+            assert!(env[&format!("GIT_CONFIG_VALUE_{}", i)].is_string());
+        }
+        assert_eq!(env.len(), 8 + 2 * count);
+        assert!(env.values().any(|value| value == "gpg.format"));
+    }
+
+    #[test]
+    fn raw_paths_keep_the_stored_tilde() {
+        let default_dir = Path::new("/data/sshkeys");
+        let mut user = User {
+            id: "work".to_string(),
+            sshkey_path: Some(PathBuf::from("~/keys/id_rsa")),
+            ..Default::default()
+        };
+        assert_eq!(get_raw_sshkey_path(&user), "~/keys/id_rsa");
+        assert_eq!(
+            user.get_sshkey_path(default_dir),
+            dirs::home_dir().unwrap().join("keys/id_rsa")
+        );
+
+        user.sshkey_path = None;
+        assert_eq!(get_raw_sshkey_path(&user), "(default) id_work");
+        assert_eq!(
+            user.get_sshkey_path(default_dir),
+            Path::new("/data/sshkeys/id_work")
+        );
+        user.agent_backed = true;
+        assert_eq!(get_raw_sshkey_path(&user), "(none)");
+    }
+
+    #[test]
+    fn id_read_from_stdin_selects_the_user_to_switch_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        Config {
+            users_file_path: dir.path().join("users.toml"),
+            default_sshkey_dir: dir.path().join("sshkeys"),
+            ..Config::default()
+        }
+        .save(&config_path)
+        .unwrap();
+        let mut gus = GitUserSwitcher::open(&config_path, None).unwrap();
+        gus.users
+            .add(User {
+                id: "work".to_string(),
+                name: "Jane Doe".to_string(),
+                email: "jane@corp.example".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let id = read_id(&mut "  work\nignored\n".as_bytes()).unwrap();
+        assert_eq!(id, "work");
+        let options = SwitchOptions {
+            temp_dir: Some(dir.path().join("sessions")),
+            ..Default::default()
+        };
+        gus.switch_user(&id, &options).unwrap();
+        let script =
+            std::fs::read_to_string(get_session_script_path(options.temp_dir.as_deref())).unwrap();
+        assert!(script.contains("export GIT_AUTHOR_EMAIL='jane@corp.example'\n"));
+
+        for input in ["", "\n", "   \n"] {
+            let err = read_id(&mut input.as_bytes()).unwrap_err();
+            assert_eq!(err.to_string(), "no user id was read from stdin");
+        }
+    }
+
+    #[test]
+    fn env_is_formatted_as_lines_or_json() {
+        let env = vec![
+            ("GUS_USER_ID".to_string(), "work".to_string()),
+            ("GIT_AUTHOR_NAME".to_string(), "Jane Doe".to_string()),
+        ];
+
+        assert_eq!(
+            format_env(env.clone(), &EnvFormat::Lines),
+            "GUS_USER_ID=work\nGIT_AUTHOR_NAME=Jane Doe"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&format_env(env, &EnvFormat::Json)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({"GUS_USER_ID": "work", "GIT_AUTHOR_NAME": "Jane Doe"})
+        );
+    }
+}
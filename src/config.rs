@@ -1,21 +1,53 @@
-use anyhow::{Context, Result};
+use anyhow::{ensure, Context, Result};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fmt::Display,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
 
 use crate::sshkey::SshKeyType;
 
 static DEFAULT_DATA_DIR: Lazy<PathBuf> = Lazy::new(|| dirs::home_dir().unwrap().join(".gus"));
 
+/// How switching commands hand the environment over to the shell
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub enum SessionMode {
+    /// Write a session script that the shell function sources
+    File,
+    /// Print the exports to stdout for the shell function to eval
+    Eval,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(default)]
 pub struct Config {
     pub users_file_path: PathBuf,
     pub default_sshkey_dir: PathBuf,
     pub default_sshkey_type: SshKeyType,
+    pub allowed_sshkey_types: Vec<SshKeyType>,
     pub force_use_gus: bool,
+    pub force_use_gus_message: Option<String>,
+    pub force_use_exempt_patterns: Vec<String>,
     pub min_sshkey_passphrase_length: usize,
     pub sign_commits: bool,
+    pub allowed_email_domains: Vec<String>,
+    pub isolate_git_config: bool,
+    pub gitconfig_dir: PathBuf,
+    pub per_user_ssh_agent: bool,
+    pub ssh_agent_dir: PathBuf,
+    pub pinned_known_hosts_dir: PathBuf,
+    pub session_mode: SessionMode,
+    pub ssh_command_templates: HashMap<String, String>,
+    pub git_path: Option<PathBuf>,
+    pub ssh_path: Option<PathBuf>,
+    pub ssh_keygen_path: Option<PathBuf>,
+    pub warn_staged_changes: bool,
 }
 
 impl Default for Config {
@@ -24,9 +56,24 @@ impl Default for Config {
             users_file_path: DEFAULT_DATA_DIR.join("users.toml"),
             default_sshkey_dir: DEFAULT_DATA_DIR.join("sshkeys/"),
             default_sshkey_type: SshKeyType::Ed25519,
+            allowed_sshkey_types: Vec::new(),
             force_use_gus: true,
+            force_use_gus_message: None,
+            force_use_exempt_patterns: Vec::new(),
             min_sshkey_passphrase_length: 10,
             sign_commits: true,
+            allowed_email_domains: Vec::new(),
+            isolate_git_config: false,
+            gitconfig_dir: DEFAULT_DATA_DIR.join("gitconfigs/"),
+            per_user_ssh_agent: false,
+            ssh_agent_dir: DEFAULT_DATA_DIR.join("agents/"),
+            pinned_known_hosts_dir: DEFAULT_DATA_DIR.join("known_hosts/"),
+            session_mode: SessionMode::File,
+            ssh_command_templates: HashMap::new(),
+            git_path: None,
+            ssh_path: None,
+            ssh_keygen_path: None,
+            warn_staged_changes: false,
         }
     }
 }
@@ -34,29 +81,135 @@ impl Default for Config {
 impl Config {
     pub fn save(&self, path: &PathBuf) -> Result<()> {
         if !path.exists() {
-            std::fs::create_dir_all(&path.parent().unwrap()).with_context(|| {
+            std::fs::create_dir_all(path.parent().unwrap()).with_context(|| {
                 format!("failed to create config directory: {}", path.display())
             })?;
         }
 
         let contents = toml::to_string(&self)
             .with_context(|| format!("failed to serialize config file: {}", path.display()))?;
-        std::fs::write(&path, contents)
+        std::fs::write(path, contents)
             .with_context(|| format!("failed to write config file: {}", path.display()))?;
         Ok(())
     }
 
     pub fn open(path: &PathBuf) -> Result<Self> {
         if !path.exists() {
+            // a read-only setup still works with the defaults, it just can't persist them
             let config = Self::default();
-            config.save(path)?;
+            if is_writable(path) {
+                config.save(path)?;
+            }
             return Ok(config);
         }
 
-        let contents = std::fs::read_to_string(&path)
+        let contents = std::fs::read_to_string(path)
             .with_context(|| format!("failed to read config file: {}", path.display()))?;
         let config = toml::from_str(&contents)
             .with_context(|| format!("failed to parse config file: {}", path.display()))?;
         Ok(config)
     }
+
+    pub fn get_git_path(&self) -> Result<PathBuf> {
+        resolve_tool_path("git_path", self.git_path.as_ref(), "git")
+    }
+
+    pub fn get_ssh_path(&self) -> Result<PathBuf> {
+        resolve_tool_path("ssh_path", self.ssh_path.as_ref(), "ssh")
+    }
+
+    pub fn get_ssh_keygen_path(&self) -> Result<PathBuf> {
+        resolve_tool_path(
+            "ssh_keygen_path",
+            self.ssh_keygen_path.as_ref(),
+            "ssh-keygen",
+        )
+    }
+
+    pub fn diff_from_default(&self, path: &PathBuf) -> Result<Vec<ConfigDiff>> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file: {}", path.display()))?;
+        let file: toml::Table = toml::from_str(&contents)
+            .with_context(|| format!("failed to parse config file: {}", path.display()))?;
+        let current = toml::Table::try_from(self).context("failed to serialize config")?;
+        let default =
+            toml::Table::try_from(Self::default()).context("failed to serialize config")?;
+
+        let mut diffs = Vec::new();
+        for (key, default_value) in default {
+            if !file.contains_key(&key) {
+                diffs.push(ConfigDiff::NotInFile {
+                    key,
+                    default: default_value,
+                });
+            } else if let Some(current_value) = current.get(&key) {
+                if *current_value != default_value {
+                    diffs.push(ConfigDiff::Changed {
+                        key,
+                        default: default_value,
+                        current: current_value.clone(),
+                    });
+                }
+            }
+        }
+        Ok(diffs)
+    }
+}
+
+pub enum ConfigDiff {
+    Changed {
+        key: String,
+        default: toml::Value,
+        current: toml::Value,
+    },
+    NotInFile {
+        key: String,
+        default: toml::Value,
+    },
+}
+
+impl Display for ConfigDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Changed {
+                key,
+                default,
+                current,
+            } => write!(f, "{} = {} ({} → {})", key, current, default, current),
+            Self::NotInFile { key, default } => {
+                write!(f, "{} = {} (default, not in config file)", key, default)
+            }
+        }
+    }
+}
+
+/// The configured path of a tool, or its name to be looked up in PATH.
+fn resolve_tool_path(key: &str, path: Option<&PathBuf>, name: &str) -> Result<PathBuf> {
+    match path {
+        Some(path) => {
+            ensure!(path.is_file(), "{} does not exist: {}", key, path.display());
+            Ok(path.clone())
+        }
+        None => Ok(PathBuf::from(name)),
+    }
+}
+
+/// Whether `path` could be written, judged by the nearest existing ancestor when it doesn't exist yet.
+/// Replaces a leading `~` with the home directory, as a shell would for an unquoted path.
+pub fn expand_tilde(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+pub fn is_writable(path: &Path) -> bool {
+    let Some(existing) = path.ancestors().find(|p| p.exists()) else {
+        return false;
+    };
+    let Ok(c_path) = CString::new(existing.as_os_str().as_bytes()) else {
+        return false;
+    };
+    unsafe { libc::access(c_path.as_ptr(), libc::W_OK) == 0 }
 }
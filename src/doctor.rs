@@ -0,0 +1,500 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::gus::GitUserSwitcher;
+use crate::shell::{get_app_path, APP_PATH_KEY};
+use crate::sshkey::{get_public_key_material, get_public_key_path, is_ssh_key_encrypted};
+use crate::user::User;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+pub struct Diagnosis {
+    pub severity: Severity,
+    pub subject: String,
+    pub message: String,
+}
+
+impl Diagnosis {
+    fn new(severity: Severity, subject: &str, message: impl Into<String>) -> Self {
+        Self {
+            severity,
+            subject: subject.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Diagnosis {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let label = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warn",
+            Severity::Error => "error",
+        };
+        write!(f, "[{}] {}: {}", label, self.subject, self.message)
+    }
+}
+
+pub fn diagnose(gus: &GitUserSwitcher, check_remote: bool) -> Vec<Diagnosis> {
+    let mut users = gus.list_users();
+    users.sort_by(|a, b| a.id.cmp(&b.id));
+
+    let mut diagnoses = Vec::new();
+    diagnose_shell(
+        std::env::var(APP_PATH_KEY).ok(),
+        &get_app_path(),
+        &mut diagnoses,
+    );
+    for user in users {
+        diagnose_user(gus, user, &mut diagnoses);
+        if check_remote {
+            diagnose_remote(gus, user, &mut diagnoses);
+        }
+    }
+    diagnose_shared_keys(gus, &mut diagnoses);
+    diagnoses
+}
+
+/// Flags users whose public keys have identical key material, even when stored under different paths.
+fn diagnose_shared_keys(gus: &GitUserSwitcher, diagnoses: &mut Vec<Diagnosis>) {
+    let mut ids_by_key: HashMap<String, Vec<&str>> = HashMap::new();
+    for user in gus.list_users() {
+        if user.agent_backed {
+            continue;
+        }
+        let pub_path = get_public_key_path(&user.get_sshkey_path(&gus.config.default_sshkey_dir));
+        let Ok(contents) = std::fs::read_to_string(&pub_path) else {
+            continue;
+        };
+        let material = get_public_key_material(&contents);
+        if !material.is_empty() {
+            ids_by_key.entry(material).or_default().push(&user.id);
+        }
+    }
+
+    let mut groups = ids_by_key
+        .into_values()
+        .filter(|ids| ids.len() > 1)
+        .collect::<Vec<_>>();
+    for ids in &mut groups {
+        ids.sort();
+    }
+    groups.sort();
+    for ids in groups {
+        diagnoses.push(Diagnosis::new(
+            Severity::Warning,
+            &ids.join(", "),
+            "users share the same ssh key material",
+        ));
+    }
+}
+
+/// `loaded_app_path` is the binary the loaded shell integration calls, if one is loaded.
+fn diagnose_shell(
+    loaded_app_path: Option<String>,
+    app_path: &Path,
+    diagnoses: &mut Vec<Diagnosis>,
+) {
+    let Some(loaded_app_path) = loaded_app_path else {
+        return;
+    };
+    if is_same_app_path(Path::new(&loaded_app_path), app_path) {
+        diagnoses.push(Diagnosis::new(
+            Severity::Ok,
+            "shell",
+            "shell integration is up to date",
+        ));
+    } else {
+        diagnoses.push(Diagnosis::new(
+            Severity::Warning,
+            "shell",
+            format!(
+                "shell integration calls {} but this is {} (restart the shell to reload it)",
+                loaded_app_path,
+                app_path.display()
+            ),
+        ));
+    }
+}
+
+fn is_same_app_path(loaded: &Path, current: &Path) -> bool {
+    match (loaded.canonicalize(), current.canonicalize()) {
+        (Ok(loaded), Ok(current)) => loaded == current,
+        _ => loaded == current,
+    }
+}
+
+fn diagnose_user(gus: &GitUserSwitcher, user: &User, diagnoses: &mut Vec<Diagnosis>) {
+    let id = user.id.as_str();
+
+    if user.agent_backed {
+        diagnoses.push(Diagnosis::new(Severity::Ok, id, "ssh key is agent-backed"));
+    } else {
+        let sshkey_path = user.get_sshkey_path(&gus.config.default_sshkey_dir);
+        if !sshkey_path.exists() {
+            diagnoses.push(Diagnosis::new(
+                Severity::Error,
+                id,
+                format!("ssh key does not exist: {}", sshkey_path.display()),
+            ));
+        } else if !get_public_key_path(&sshkey_path).exists() {
+            diagnoses.push(Diagnosis::new(
+                Severity::Warning,
+                id,
+                format!(
+                    "public ssh key does not exist: {}",
+                    get_public_key_path(&sshkey_path).display()
+                ),
+            ));
+        } else {
+            diagnoses.push(Diagnosis::new(
+                Severity::Ok,
+                id,
+                format!("ssh key exists: {}", sshkey_path.display()),
+            ));
+        }
+
+        if sshkey_path.exists() {
+            match is_ssh_key_encrypted(&sshkey_path) {
+                Ok(true) => {}
+                Ok(false) => diagnoses.push(Diagnosis::new(
+                    Severity::Warning,
+                    id,
+                    "ssh key is not protected by a passphrase",
+                )),
+                Err(err) => {
+                    diagnoses.push(Diagnosis::new(Severity::Warning, id, format!("{:#}", err)))
+                }
+            }
+        }
+    }
+
+    if let Some(known_hosts) = &user.known_hosts {
+        if !known_hosts.is_file() {
+            diagnoses.push(Diagnosis::new(
+                Severity::Warning,
+                id,
+                format!("known_hosts file does not exist: {}", known_hosts.display()),
+            ));
+        }
+    }
+
+    if user.require_signing
+        && user
+            .get_signing_key(&gus.config.default_sshkey_dir)
+            .is_none()
+    {
+        diagnoses.push(Diagnosis::new(
+            Severity::Warning,
+            id,
+            "signing is required but no signing key is configured",
+        ));
+    }
+
+    if let Some(activate_script) = &user.activate_script {
+        if !activate_script.is_file() {
+            diagnoses.push(Diagnosis::new(
+                Severity::Error,
+                id,
+                format!(
+                    "activate script does not exist: {}",
+                    activate_script.display()
+                ),
+            ));
+        }
+    }
+}
+
+fn diagnose_remote(gus: &GitUserSwitcher, user: &User, diagnoses: &mut Vec<Diagnosis>) {
+    let id = user.id.as_str();
+    let host = user.get_host();
+    match gus.test_connection(id, None, true) {
+        Ok((true, _)) => diagnoses.push(Diagnosis::new(
+            Severity::Ok,
+            id,
+            format!("authenticated to {}", host),
+        )),
+        Ok((false, message)) => diagnoses.push(Diagnosis::new(
+            Severity::Error,
+            id,
+            format!("failed to authenticate to {}: {}", host, message),
+        )),
+        Err(err) => diagnoses.push(Diagnosis::new(
+            Severity::Error,
+            id,
+            format!("failed to authenticate to {}: {:#}", host, err),
+        )),
+    }
+}
+
+/// Finds git repositories under `root` and checks which gus user each one commits as.
+/// Repositories are not descended into, and the walk stops at `max_depth` levels below `root`.
+pub fn scan_repos(gus: &GitUserSwitcher, root: &Path, max_depth: usize) -> Result<Vec<Diagnosis>> {
+    let git = gus.config.get_git_path()?;
+    let mut repos = Vec::new();
+    find_repos(root, max_depth, &mut repos);
+    repos.sort();
+
+    Ok(repos
+        .iter()
+        .map(|repo| {
+            let subject = repo.display().to_string();
+            let Some(email) = get_repo_email(&git, repo) else {
+                return Diagnosis::new(Severity::Warning, &subject, "user.email is not set");
+            };
+            match gus.users.find_by_email(&email).first() {
+                Some(user) => Diagnosis::new(
+                    Severity::Ok,
+                    &subject,
+                    format!("commits as {} (user '{}')", email, user.id),
+                ),
+                None => Diagnosis::new(
+                    Severity::Warning,
+                    &subject,
+                    format!("commits as {} which is not a gus user", email),
+                ),
+            }
+        })
+        .collect())
+}
+
+fn find_repos(dir: &Path, depth: usize, repos: &mut Vec<PathBuf>) {
+    if dir.join(".git").exists() {
+        repos.push(dir.to_path_buf());
+        return;
+    }
+    if depth == 0 {
+        return;
+    }
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        // file_type() doesn't follow symlinks, so links can't make the walk loop
+        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            find_repos(&entry.path(), depth - 1, repos);
+        }
+    }
+}
+
+fn get_repo_email(git: &Path, repo: &Path) -> Option<String> {
+    let output = Command::new(git)
+        .arg("-C")
+        .arg(repo)
+        .args(["config", "--get", "user.email"])
+        .output()
+        .ok()?;
+    let email = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !email.is_empty()).then_some(email)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::user::SignFormat;
+
+    fn switcher(dir: &Path) -> GitUserSwitcher {
+        let config_path = dir.join("config.toml");
+        Config {
+            users_file_path: dir.join("users.toml"),
+            default_sshkey_dir: dir.join("sshkeys"),
+            ..Config::default()
+        }
+        .save(&config_path)
+        .unwrap();
+        GitUserSwitcher::open(&config_path, None).unwrap()
+    }
+
+    fn messages(gus: &GitUserSwitcher, user: &User) -> Vec<String> {
+        let mut diagnoses = Vec::new();
+        diagnose_user(gus, user, &mut diagnoses);
+        diagnoses
+            .into_iter()
+            .filter(|diagnosis| diagnosis.severity != Severity::Ok)
+            .map(|diagnosis| diagnosis.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn required_signing_without_a_key_is_warned_about() {
+        let dir = tempfile::tempdir().unwrap();
+        let gus = switcher(dir.path());
+        let mut user = User {
+            id: "work".to_string(),
+            agent_backed: true,
+            require_signing: true,
+            ..Default::default()
+        };
+        let warning = "[warn] work: signing is required but no signing key is configured";
+        assert!(messages(&gus, &user).contains(&warning.to_string()));
+
+        user.signing_key = Some("ABCDEF".to_string());
+        assert!(!messages(&gus, &user).contains(&warning.to_string()));
+
+        user.signing_key = None;
+        user.agent_backed = false;
+        user.sign_format = Some(SignFormat::Ssh);
+        assert!(!messages(&gus, &user).contains(&warning.to_string()));
+    }
+
+    #[test]
+    fn unencrypted_ssh_keys_are_warned_about() {
+        let dir = tempfile::tempdir().unwrap();
+        let gus = switcher(dir.path());
+        let user = User {
+            id: "work".to_string(),
+            ..Default::default()
+        };
+        let sshkey_path = dir.path().join("sshkeys/id_work");
+        std::fs::create_dir_all(sshkey_path.parent().unwrap()).unwrap();
+        let warning = "[warn] work: ssh key is not protected by a passphrase".to_string();
+
+        for (passphrase, warned) in [("", true), ("correct horse", false)] {
+            let _ = std::fs::remove_file(&sshkey_path);
+            let _ = std::fs::remove_file(get_public_key_path(&sshkey_path));
+            let status = Command::new("ssh-keygen")
+                .args(["-q", "-t", "ed25519", "-N", passphrase, "-f"])
+                .arg(&sshkey_path)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            assert_eq!(messages(&gus, &user).contains(&warning), warned);
+        }
+    }
+
+    fn init_repo(dir: &Path, email: &str) {
+        std::fs::create_dir_all(dir).unwrap();
+        for args in [&["init", "-q"][..], &["config", "user.email", email][..]] {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(dir)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+    }
+
+    #[test]
+    fn scan_reports_the_identity_of_each_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users
+            .add(User {
+                id: "work".to_string(),
+                email: "jane@corp.example".to_string(),
+                ..Default::default()
+            })
+            .unwrap();
+        let root = dir.path().join("src");
+        init_repo(&root.join("app"), "jane@corp.example");
+        init_repo(&root.join("oss/lib"), "jane@elsewhere.example");
+        init_repo(&root.join("a/b/c/too-deep"), "jane@corp.example");
+        std::os::unix::fs::symlink(&root, root.join("oss/loop")).unwrap();
+
+        let report = scan_repos(&gus, &root, 2)
+            .unwrap()
+            .iter()
+            .map(|diagnosis| diagnosis.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            report,
+            [
+                format!(
+                    "[ok] {}: commits as jane@corp.example (user 'work')",
+                    root.join("app").display()
+                ),
+                format!(
+                    "[warn] {}: commits as jane@elsewhere.example which is not a gus user",
+                    root.join("oss/lib").display()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn shell_integration_drift_is_detected_from_the_loaded_app_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let app = dir.path().join("bin/gus");
+        std::fs::create_dir_all(app.parent().unwrap()).unwrap();
+        std::fs::write(&app, "").unwrap();
+        let link = dir.path().join("gus-link");
+        std::os::unix::fs::symlink(&app, &link).unwrap();
+
+        assert!(is_same_app_path(&app, &app));
+        assert!(is_same_app_path(&link, &app));
+        assert!(is_same_app_path(&dir.path().join("bin/../bin/gus"), &app));
+        assert!(!is_same_app_path(&dir.path().join("old/gus"), &app));
+        assert!(is_same_app_path(
+            Path::new("/missing/gus"),
+            Path::new("/missing/gus")
+        ));
+
+        let diagnose = |loaded: Option<&Path>| {
+            let mut diagnoses = Vec::new();
+            diagnose_shell(
+                loaded.map(|path| path.to_string_lossy().to_string()),
+                &app,
+                &mut diagnoses,
+            );
+            diagnoses
+                .iter()
+                .map(|diagnosis| diagnosis.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert!(diagnose(None).is_empty());
+        assert_eq!(
+            diagnose(Some(&link)),
+            ["[ok] shell: shell integration is up to date"]
+        );
+        assert_eq!(
+            diagnose(Some(&dir.path().join("old/gus"))),
+            [format!(
+                "[warn] shell: shell integration calls {} but this is {} (restart the shell to reload it)",
+                dir.path().join("old/gus").display(),
+                app.display()
+            )]
+        );
+    }
+
+    #[test]
+    fn identical_public_keys_at_different_paths_are_flagged() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        let sshkey_dir = dir.path().join("sshkeys");
+        std::fs::create_dir_all(&sshkey_dir).unwrap();
+        for (id, material) in [
+            ("home", "AAAAshared"),
+            ("work", "AAAAshared"),
+            ("oss", "AAAAother"),
+        ] {
+            gus.users
+                .add(User {
+                    id: id.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+            let public_key = format!("ssh-ed25519 {} jane@example.com\n", material);
+            std::fs::write(sshkey_dir.join(format!("id_{}.pub", id)), public_key).unwrap();
+        }
+
+        let mut diagnoses = Vec::new();
+        diagnose_shared_keys(&gus, &mut diagnoses);
+        let messages = diagnoses
+            .iter()
+            .map(|diagnosis| diagnosis.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            ["[warn] home, work: users share the same ssh key material"]
+        );
+    }
+}
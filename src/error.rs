@@ -0,0 +1,39 @@
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Failures looking up or adding users; match on them with `anyhow::Error::downcast_ref`.
+#[derive(Debug, Error)]
+pub enum UserError {
+    #[error("user with id '{0}' does not exist")]
+    NotFound(String),
+
+    #[error("user with id, email or name '{0}' does not exist")]
+    NoMatch(String),
+
+    #[error("user with id '{0}' already exists")]
+    AlreadyExists(String),
+
+    /// `candidates` holds the matching users as they are listed, sorted by ID.
+    #[error("'{selector}' matches multiple users:\n{}", indent_lines(.candidates))]
+    Ambiguous {
+        selector: String,
+        candidates: Vec<String>,
+    },
+}
+
+fn indent_lines(lines: &[String]) -> String {
+    lines
+        .iter()
+        .map(|line| format!("  {}", line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[derive(Debug, Error)]
+pub enum KeyError {
+    #[error("ssh key does not exist: {}", .0.display())]
+    Missing(PathBuf),
+
+    #[error("ssh key already exists at path: {}", .0.display())]
+    AlreadyExists(PathBuf),
+}
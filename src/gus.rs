@@ -1,98 +1,940 @@
-use anyhow::{ensure, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use clap::Args;
 use std::env;
-use std::path::PathBuf;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
 
-use crate::config::{self, Config};
-use crate::shell::{get_app_name, get_app_path, get_setup_script, write_session_script};
-use crate::sshkey::generate_ssh_key;
-use crate::user::{User, Users};
+use crate::config::{is_writable, Config, SessionMode};
+use crate::error::{KeyError, UserError};
+use crate::shell::{
+    fish_single_quote, get_app_name, get_setup_script, shell_glob_escape, shell_single_quote,
+    write_session_script, Shell,
+};
+use crate::sshkey::{
+    add_to_agent, backup_ssh_key, derive_public_key, fetch_github_keys, generate_ssh_key,
+    get_public_key_path, is_same_public_key, restore_ssh_key, test_connection, SshKeyType,
+};
+use crate::timing;
+use crate::user::{Protocol, SignFormat, User, Users};
+
+/// The session's co-authors as `Name <email>` lines; kept across `set` and `unset`.
+const COAUTHORS_KEY: &str = "GUS_COAUTHORS";
+
+const SESSION_ENV_KEYS: [&str; 7] = [
+    "GUS_USER_ID",
+    "GIT_AUTHOR_NAME",
+    "GIT_AUTHOR_EMAIL",
+    "GIT_COMMITTER_NAME",
+    "GIT_COMMITTER_EMAIL",
+    "GIT_SSH_COMMAND",
+    "GIT_CONFIG_GLOBAL",
+];
+
+/// How many of the trailing GIT_CONFIG_KEY_n/VALUE_n entries were exported by gus
+const GUS_GIT_CONFIG_COUNT_KEY: &str = "GUS_GIT_CONFIG_COUNT";
+
+#[derive(Args, Default)]
+pub struct SwitchOptions {
+    /// Create the user's known_hosts file if it does not exist
+    #[clap(long)]
+    pub create_known_hosts: bool,
+
+    /// Only set the committer, keeping the author of commits as is
+    #[clap(long)]
+    pub as_committer_only: bool,
+
+    /// Write the session script to this directory instead of the default one.
+    /// The shell wrapper does not source it; use --source or source it yourself.
+    #[clap(long)]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Make ssh verbose for debugging authentication; repeat for more detail
+    #[clap(long, action = clap::ArgAction::Count)]
+    pub ssh_verbose: u8,
+}
+
+#[derive(Args, Default)]
+pub struct UserEdit {
+    /// The new name of the user
+    #[clap(long)]
+    pub name: Option<String>,
+
+    /// The new email of the user
+    #[clap(long)]
+    pub email: Option<String>,
+
+    /// The path to an existing ssh key to use instead of the current one
+    #[clap(long)]
+    pub sshkey_path: Option<PathBuf>,
+
+    /// The new key used to sign commits and tags
+    #[clap(long)]
+    pub signing_key: Option<String>,
+
+    /// The new signature format
+    #[clap(long)]
+    pub sign_format: Option<SignFormat>,
+}
 
 pub struct GitUserSwitcher {
     pub users: Users,
     pub config: Config,
+    config_path: Option<PathBuf>,
+    read_only: bool,
+    /// Set by `--users-file`; the override is never written back to the config
+    users_file_overridden: bool,
 }
 
-impl From<&PathBuf> for GitUserSwitcher {
-    fn from(config_path: &PathBuf) -> Self {
-        let mut org_config = config_path.clone();
-        org_config.set_extension("default.toml");
-        Config::default().save(&org_config);
-        let config = Config::open(config_path).unwrap();
-        let users = Users::open(&config.users_file_path).unwrap();
-        Self { users, config }
+fn absolute_path(path: &Path) -> PathBuf {
+    std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn resolve_config_paths(config: &mut Config) {
+    config.users_file_path = absolute_path(&config.users_file_path);
+    config.default_sshkey_dir = absolute_path(&config.default_sshkey_dir);
+    config.gitconfig_dir = absolute_path(&config.gitconfig_dir);
+    config.ssh_agent_dir = absolute_path(&config.ssh_agent_dir);
+    config.pinned_known_hosts_dir = absolute_path(&config.pinned_known_hosts_dir);
+}
+
+/// Points the config at `users_file`, if given, so the default users file is never read.
+fn override_users_file_path(config: &mut Config, users_file: Option<&Path>) -> bool {
+    match users_file {
+        Some(path) => {
+            config.users_file_path = absolute_path(path);
+            true
+        }
+        None => false,
     }
 }
 
 impl GitUserSwitcher {
-    pub fn add_user(&mut self, user: User, sshkey_passphrase: Option<&str>) -> Result<()> {
+    /// Opens the config at `config_path` and the users it points to, or the users in
+    /// `users_file` (`--users-file`) instead.
+    pub fn open(config_path: &Path, users_file: Option<&Path>) -> Result<Self> {
+        // resolve once so that later saves don't depend on the current directory
+        let config_path = absolute_path(config_path);
+        let mut org_config = config_path.clone();
+        org_config.set_extension("default.toml");
+        if is_writable(&org_config) {
+            let _ = Config::default().save(&org_config);
+        }
+        let mut config = timing::time("config load", || Config::open(&config_path))?;
+        resolve_config_paths(&mut config);
+        let users_file_overridden = override_users_file_path(&mut config, users_file);
+        let users = timing::time("users load", || Users::open(&config.users_file_path))?;
+        Ok(Self {
+            users,
+            config,
+            config_path: Some(config_path),
+            read_only: false,
+            users_file_overridden,
+        })
+    }
+
+    /// Reads the config from `reader` (stdin). Nothing is written back, so users and keys can't
+    /// be modified.
+    pub fn from_reader(mut reader: impl Read, users_file: Option<&Path>) -> Result<Self> {
+        let mut contents = String::new();
+        reader
+            .read_to_string(&mut contents)
+            .context("failed to read config from stdin")?;
+        let mut config: Config =
+            toml::from_str(&contents).context("failed to parse config from stdin")?;
+        resolve_config_paths(&mut config);
+        let users_file_overridden = override_users_file_path(&mut config, users_file);
+        let users = if config.users_file_path.exists() {
+            Users::open(&config.users_file_path)?
+        } else {
+            Users::new()
+        };
+        Ok(Self {
+            users,
+            config,
+            config_path: None,
+            read_only: true,
+            users_file_overridden,
+        })
+    }
+
+    /// Applies `update` to the config file as stored, so that paths resolved at startup and
+    /// a `--users-file` override are not written back.
+    fn update_config(&self, update: impl FnOnce(&mut Config)) -> Result<()> {
+        let config_path = self
+            .config_path
+            .as_ref()
+            .context("the config is not backed by a file")?;
+        let mut config = Config::open(config_path)?;
+        update(&mut config);
+        config.save(config_path)
+    }
+
+    fn ensure_writable(&self) -> Result<()> {
+        ensure!(
+            !self.read_only,
+            "users and keys cannot be modified when the config is read from stdin"
+        );
+        for path in [
+            &self.config.users_file_path,
+            &self.config.default_sshkey_dir,
+        ] {
+            ensure!(
+                is_writable(path),
+                "cannot write to {}: permission denied (is it on a read-only mount or owned by another user?)",
+                path.display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Adds `user`, generating its ssh key when needed. `force` accepts an email outside the
+    /// allowed domains and `allow_shared_key` a key that another user already has.
+    pub fn add_user(
+        &mut self,
+        user: User,
+        sshkey_passphrase: Option<&str>,
+        force: bool,
+        allow_shared_key: bool,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        self.check_email_domain(&user.email, force)?;
+        self.check_shared_sshkey(&user, allow_shared_key)?;
+        ensure!(
+            !user.passphrase_keychain || cfg!(feature = "keychain"),
+            "gus was built without keychain support (enable the 'keychain' feature)"
+        );
+        if let Some(activate_script) = &user.activate_script {
+            read_activate_script(activate_script)?;
+        }
+        self.validate_ssh_template(&user)?;
+        if let Some(pin) = &user.host_key_pin {
+            validate_host_key_pin(pin)?;
+        }
+
         self.users.add(user.clone())?;
 
         let sshkey_path = user.get_sshkey_path(&self.config.default_sshkey_dir);
 
-        if !sshkey_path.exists() {
+        if !user.agent_backed && !sshkey_path.exists() {
             let pass = sshkey_passphrase.context("ssh key passphrase required")?;
-            ensure!(
-                pass.len() >= self.config.min_sshkey_passphrase_length,
-                "ssh key passphrase must be at least {} characters",
-                self.config.min_sshkey_passphrase_length
-            );
+            self.generate_user_sshkey(&user, pass, &sshkey_path)?;
+        }
 
+        #[cfg(feature = "keychain")]
+        if user.passphrase_keychain {
+            let pass = sshkey_passphrase.context("ssh key passphrase required")?;
+            crate::keychain::store_passphrase(&user.id, pass)?;
+        }
+
+        self.users.save(&self.config.users_file_path)?;
+        Ok(())
+    }
+
+    /// Generates a key of the configured type for `user` at `sshkey_path`.
+    fn generate_user_sshkey(
+        &self,
+        user: &User,
+        sshkey_passphrase: &str,
+        sshkey_path: &PathBuf,
+    ) -> Result<()> {
+        ensure!(
+            sshkey_passphrase.len() >= self.config.min_sshkey_passphrase_length,
+            "ssh key passphrase must be at least {} characters",
+            self.config.min_sshkey_passphrase_length
+        );
+        let sshkey_type = self.get_sshkey_type()?;
+        let ssh_keygen = self.config.get_ssh_keygen_path()?;
+
+        timing::time("ssh-keygen", || {
             generate_ssh_key(
-                self.config.default_sshkey_type.clone(),
-                &user.get_sshkey_name(),
-                &pass,
-                &sshkey_path,
+                &ssh_keygen,
+                sshkey_type.clone(),
+                &user.get_sshkey_comment(),
+                sshkey_passphrase,
+                sshkey_path,
             )
-            .with_context(|| format!("failed to generate ssh key for user: {}", &user.id))?;
+        })
+        .with_context(|| format!("failed to generate ssh key for user: {}", user.id))
+    }
+
+    /// Returns another user whose resolved ssh key path is the same as `user`'s.
+    pub fn find_user_sharing_sshkey(&self, user: &User) -> Option<&User> {
+        if user.agent_backed {
+            return None;
         }
+        let sshkey_path = user.get_sshkey_path(&self.config.default_sshkey_dir);
+        self.users.list().into_iter().find(|other| {
+            other.id != user.id
+                && !other.agent_backed
+                && other.get_sshkey_path(&self.config.default_sshkey_dir) == sshkey_path
+        })
+    }
 
-        self.users.save(&self.config.users_file_path)?;
+    /// Fails when another user already uses `user`'s ssh key, unless `allow_shared_key` is set.
+    pub fn check_shared_sshkey(&self, user: &User, allow_shared_key: bool) -> Result<()> {
+        if let Some(other) = self.find_user_sharing_sshkey(user) {
+            ensure!(
+                allow_shared_key,
+                "user '{}' already uses this ssh key (use --allow-shared-key to share it)",
+                other.id
+            );
+            eprintln!("warning: sharing the ssh key of user '{}'", other.id);
+        }
         Ok(())
     }
 
-    pub fn remove_user(&mut self, id: &str) -> Result<()> {
+    /// The bits of an allowed_sshkey_types entry are the smallest size allowed for its algorithm.
+    fn get_sshkey_type(&self) -> Result<&SshKeyType> {
+        let sshkey_type = &self.config.default_sshkey_type;
         ensure!(
-            self.users.exists(id),
-            "user with id '{}' does not exist",
-            id
+            self.config.allowed_sshkey_types.is_empty()
+                || self
+                    .config
+                    .allowed_sshkey_types
+                    .iter()
+                    .any(|allowed| sshkey_type.is_allowed_by(allowed)),
+            "ssh key type '{}' is not allowed by allowed_sshkey_types",
+            sshkey_type
+        );
+        sshkey_type.validate_bits()?;
+        Ok(sshkey_type)
+    }
+
+    pub fn validate_email_domain(&self, email: &str) -> Result<()> {
+        if self.config.allowed_email_domains.is_empty() {
+            return Ok(());
+        }
+
+        let domain = email
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .unwrap_or("");
+        ensure!(
+            self.config
+                .allowed_email_domains
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(domain)),
+            "email domain '{}' is not allowed (allowed domains: {})",
+            domain,
+            self.config.allowed_email_domains.join(", ")
         );
-        self.users.remove(id);
+        Ok(())
+    }
+
+    /// Like `validate_email_domain`, but only warns about a disallowed domain with `force`.
+    pub fn check_email_domain(&self, email: &str, force: bool) -> Result<()> {
+        if let Err(err) = self.validate_email_domain(email) {
+            ensure!(force, err);
+            eprintln!("warning: {} (forced)", err);
+        }
+        Ok(())
+    }
+
+    pub fn remove_user(&mut self, id: &str) -> Result<()> {
+        self.ensure_writable()?;
+        ensure!(self.users.exists(id), UserError::NotFound(id.to_string()));
+        let user = self.users.remove(id).unwrap();
         self.users.save(&self.config.users_file_path)?;
+
+        #[cfg(feature = "keychain")]
+        if user.passphrase_keychain {
+            crate::keychain::delete_passphrase(id)?;
+        }
+        #[cfg(not(feature = "keychain"))]
+        let _ = user;
+
         Ok(())
     }
 
-    pub fn switch_user(&self, id: &str) -> Result<()> {
+    /// Updates only the fields given in `edit`, keeping the rest of the user as is.
+    /// `force` accepts an email outside the allowed domains and `allow_shared_key` a key that
+    /// another user already has.
+    pub fn edit_user(
+        &mut self,
+        id: &str,
+        edit: UserEdit,
+        force: bool,
+        allow_shared_key: bool,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        ensure!(self.users.exists(id), UserError::NotFound(id.to_string()));
         ensure!(
-            self.users.exists(id),
-            "user with id '{}' does not exist",
-            id
+            edit.name.is_some()
+                || edit.email.is_some()
+                || edit.sshkey_path.is_some()
+                || edit.signing_key.is_some()
+                || edit.sign_format.is_some(),
+            "nothing to edit (use --name, --email, --sshkey-path, --signing-key or --sign-format)"
         );
-        let user = self.users.get(id).unwrap();
+        if let Some(email) = &edit.email {
+            self.check_email_domain(email, force)?;
+        }
+        if let Some(sshkey_path) = &edit.sshkey_path {
+            ensure!(
+                !self.users.get(id).unwrap().agent_backed,
+                "user '{}' has an agent-backed ssh key which has no key file",
+                id
+            );
+            ensure!(
+                sshkey_path.is_file(),
+                KeyError::Missing(sshkey_path.clone())
+            );
+            let mut edited = self.users.get(id).unwrap().clone();
+            edited.set_primary_key(absolute_path(sshkey_path));
+            self.check_shared_sshkey(&edited, allow_shared_key)?;
+        }
 
-        let script = format!(
+        let user = self.users.get_mut(id).unwrap();
+        if let Some(name) = edit.name {
+            user.name = name;
+        }
+        if let Some(email) = edit.email {
+            user.email = email;
+        }
+        if let Some(sshkey_path) = edit.sshkey_path {
+            user.set_primary_key(absolute_path(&sshkey_path));
+        }
+        if let Some(signing_key) = edit.signing_key {
+            user.signing_key = Some(signing_key);
+        }
+        if let Some(sign_format) = edit.sign_format {
+            user.sign_format = Some(sign_format);
+        }
+        self.users.save(&self.config.users_file_path)
+    }
+
+    /// Moves a user to `new_id`. Default-named key files are renamed along with the ID, since
+    /// the default key name is derived from it; so are the pinned known_hosts file and agent socket.
+    pub fn rename_user(&mut self, old_id: &str, new_id: &str) -> Result<()> {
+        self.ensure_writable()?;
+        ensure!(
+            self.users.exists(old_id),
+            UserError::NotFound(old_id.to_string())
+        );
+        ensure!(
+            !self.users.exists(new_id),
+            UserError::AlreadyExists(new_id.to_string())
+        );
+
+        let old_user = self.users.get(old_id).unwrap().clone();
+        let mut user = old_user.clone();
+        user.id = new_id.to_string();
+
+        let mut moves = Vec::new();
+        if !user.agent_backed && user.primary_key().is_none() {
+            let old_path = old_user.get_sshkey_path(&self.config.default_sshkey_dir);
+            let new_path = user.get_sshkey_path(&self.config.default_sshkey_dir);
+            moves.push((
+                get_public_key_path(&old_path),
+                get_public_key_path(&new_path),
+            ));
+            moves.push((old_path, new_path));
+        }
+        moves.push((
+            self.get_pinned_known_hosts_path(&old_user),
+            self.get_pinned_known_hosts_path(&user),
+        ));
+        moves.push((
+            self.get_ssh_agent_socket_path(&old_user),
+            self.get_ssh_agent_socket_path(&user),
+        ));
+        moves.retain(|(from, _)| from.exists());
+        for (_, to) in &moves {
+            ensure!(!to.exists(), "file already exists: {}", to.display());
+        }
+        for (i, (from, to)) in moves.iter().enumerate() {
+            if let Err(err) = std::fs::rename(from, to) {
+                for (from, to) in &moves[..i] {
+                    let _ = std::fs::rename(to, from);
+                }
+                return Err(err).with_context(|| format!("failed to move: {}", from.display()));
+            }
+        }
+        // regenerated on the next switch, and would otherwise name the old ID
+        let _ = std::fs::remove_file(self.get_gitconfig_path(&old_user));
+        self.users.remove(old_id);
+
+        #[cfg(feature = "keychain")]
+        if user.passphrase_keychain {
+            let pass = crate::keychain::get_passphrase(old_id)?;
+            crate::keychain::store_passphrase(new_id, &pass)?;
+            crate::keychain::delete_passphrase(old_id)?;
+        }
+
+        self.users.add(user)?;
+        self.users.save(&self.config.users_file_path)
+    }
+
+    /// Fixes users whose table key in the users file differs from their `id` field.
+    pub fn repair_users_file(&mut self, use_inner_id: bool) -> Result<Vec<(String, String)>> {
+        self.ensure_writable()?;
+        let repaired = self.users.repair_ids(use_inner_id)?;
+        if !repaired.is_empty() {
+            self.users.save(&self.config.users_file_path)?;
+        }
+        Ok(repaired)
+    }
+
+    /// Finds a user by ID, falling back to an exact email and then an exact name match.
+    pub fn find_user(&self, selector: &str) -> Result<&User> {
+        Ok(self.users.find(selector)?)
+    }
+
+    pub fn switch_user(&self, selector: &str, options: &SwitchOptions) -> Result<()> {
+        let user = self.find_user(selector)?;
+        self.validate_ssh_template(user)?;
+
+        if let Some(known_hosts) = &user.known_hosts {
+            if !known_hosts.exists() && options.create_known_hosts {
+                if let Some(parent) = known_hosts.parent() {
+                    std::fs::create_dir_all(parent).with_context(|| {
+                        format!(
+                            "failed to create known_hosts directory: {}",
+                            parent.display()
+                        )
+                    })?;
+                }
+                std::fs::write(known_hosts, "").with_context(|| {
+                    format!(
+                        "failed to create known_hosts file: {}",
+                        known_hosts.display()
+                    )
+                })?;
+            }
+            ensure!(
+                known_hosts.is_file(),
+                "known_hosts file does not exist: {} (use --create-known-hosts to create it)",
+                known_hosts.display()
+            );
+        }
+
+        if self.config.warn_staged_changes
+            && env::var("GUS_USER_ID").ok().as_deref() != Some(user.id.as_str())
+            && has_staged_changes(&self.config.get_git_path()?, Path::new("."))
+        {
+            eprintln!(
+                "warning: this repository has staged changes which will be committed as '{}'",
+                user.id
+            );
+        }
+
+        if self.config.isolate_git_config {
+            self.write_isolated_gitconfig(user)?;
+        }
+
+        let script = self.build_session_script(user, options)?;
+        self.emit_session_script(&script, options.temp_dir.as_deref())
+    }
+
+    /// Writes the user's identity into the git config of the repository at `dir`, so that tools
+    /// outside the shell see it too. `scope` is "local" or "worktree"; git resolves linked
+    /// worktrees itself. Returns the keys that were written.
+    pub fn switch_user_local(
+        &self,
+        selector: &str,
+        dir: &Path,
+        scope: &str,
+    ) -> Result<Vec<String>> {
+        let user = self.find_user(selector)?;
+        self.validate_ssh_template(user)?;
+        let git = self.config.get_git_path()?;
+        ensure!(
+            is_inside_work_tree(&git, dir),
+            "not inside a git work tree; --local needs a repository"
+        );
+        self.write_pinned_known_hosts(user)?;
+
+        let mut config = vec![
+            ("user.name".to_string(), user.name.clone()),
+            ("user.email".to_string(), user.email.clone()),
+            (
+                "core.sshCommand".to_string(),
+                self.get_ssh_command(user, user.ssh_verbose.unwrap_or(0))?,
+            ),
+        ];
+        config
+            .extend(user.get_git_config(&self.config.default_sshkey_dir, self.config.sign_commits));
+        for (key, value) in &config {
+            set_git_config(&git, dir, scope, key, value)?;
+        }
+        Ok(config.into_iter().map(|(key, _)| key).collect())
+    }
+
+    /// Runs `command` with the user's environment applied, as a shell would see it after switching.
+    pub fn run_as_user(
+        &self,
+        selector: &str,
+        options: &SwitchOptions,
+        command: &[String],
+    ) -> Result<ExitStatus> {
+        let user = self.find_user(selector)?;
+        let (program, args) = command.split_first().context("no command given")?;
+        Command::new(program)
+            .args(args)
+            .envs(self.build_env(user, options)?)
+            .status()
+            .with_context(|| format!("failed to run command: {}", program))
+    }
+
+    /// Runs `git clone` with the user's environment, rewriting `url` to the user's preferred protocol.
+    pub fn clone_repo(&self, selector: &str, url: &str, args: &[String]) -> Result<ExitStatus> {
+        let user = self.find_user(selector)?;
+        let url = match user.preferred_protocol {
+            Some(protocol) => convert_clone_url(url, protocol),
+            None => url.to_string(),
+        };
+        Command::new(self.config.get_git_path()?)
+            .arg("clone")
+            .arg(&url)
+            .args(args)
+            .envs(self.build_env(user, &SwitchOptions::default())?)
+            .status()
+            .context("failed to run git clone")
+    }
+
+    pub fn get_session_script(&self, selector: &str, options: &SwitchOptions) -> Result<String> {
+        let user = self.find_user(selector)?;
+        self.build_session_script(user, options)
+    }
+
+    fn build_session_script(&self, user: &User, options: &SwitchOptions) -> Result<String> {
+        let env = self.build_env(user, options)?;
+        let shell = Shell::of_caller();
+
+        // Clear variables left over from the previous user that this one does not set.
+        let mut script = SESSION_ENV_KEYS
+            .iter()
+            .filter(|key| !env.iter().any(|(k, _)| k == *key))
+            .filter(|key| !(options.as_committer_only && key.starts_with("GIT_AUTHOR_")))
+            .map(|key| shell.format_unset(key))
+            .collect::<String>();
+        let (base, count) = get_caller_git_config_counts();
+        let keep = env
+            .iter()
+            .find(|(key, _)| key == GUS_GIT_CONFIG_COUNT_KEY)
+            .map_or(0, |(_, value)| value.parse().unwrap());
+        script.push_str(&format_git_config_cleanup(shell, base, count, keep));
+        script.push_str(&format_exports(shell, &env));
+
+        if let Some(activate_script) = &user.activate_script {
+            script.push_str(&read_activate_script(activate_script)?);
+            script.push('\n');
+        }
+
+        Ok(script)
+    }
+
+    /// An .envrc snippet exporting the user's environment; direnv restores it on leaving the directory.
+    pub fn get_direnv_script(&self, selector: &str) -> Result<String> {
+        let user = self.find_user(selector)?;
+        let mut script = format!("# gus: {}\n", user.id);
+        script.push_str(&format_exports(
+            Shell::Bash,
+            &self.build_env(user, &SwitchOptions::default())?,
+        ));
+        Ok(script)
+    }
+
+    /// Also writes the pinned known_hosts file that the ssh command points at.
+    pub fn build_env(&self, user: &User, options: &SwitchOptions) -> Result<Vec<(String, String)>> {
+        self.write_pinned_known_hosts(user)?;
+        let mut env = vec![("GUS_USER_ID".to_string(), user.id.clone())];
+        if !options.as_committer_only {
+            env.push(("GIT_AUTHOR_NAME".to_string(), user.name.clone()));
+            env.push(("GIT_AUTHOR_EMAIL".to_string(), user.email.clone()));
+        }
+        env.push(("GIT_COMMITTER_NAME".to_string(), user.name.clone()));
+        env.push(("GIT_COMMITTER_EMAIL".to_string(), user.email.clone()));
+        let ssh_verbose = options.ssh_verbose.max(user.ssh_verbose.unwrap_or(0));
+        env.push((
+            "GIT_SSH_COMMAND".to_string(),
+            self.get_ssh_command(user, ssh_verbose)?,
+        ));
+        if self.config.isolate_git_config {
+            env.push((
+                "GIT_CONFIG_GLOBAL".to_string(),
+                self.get_gitconfig_path(user).to_string_lossy().to_string(),
+            ));
+        }
+
+        let git_config =
+            user.get_git_config(&self.config.default_sshkey_dir, self.config.sign_commits);
+        let (base, _) = get_caller_git_config_counts();
+        env.extend(get_git_config_env(git_config, base));
+        Ok(env)
+    }
+
+    fn get_gitconfig_path(&self, user: &User) -> PathBuf {
+        self.config
+            .gitconfig_dir
+            .join(format!("{}.gitconfig", user.id))
+    }
+
+    fn write_isolated_gitconfig(&self, user: &User) -> Result<()> {
+        let path = self.get_gitconfig_path(user);
+        std::fs::create_dir_all(&self.config.gitconfig_dir).with_context(|| {
+            format!(
+                "failed to create gitconfig directory: {}",
+                self.config.gitconfig_dir.display()
+            )
+        })?;
+
+        let contents = format!(
             "\
-            export GUS_USER_ID=\"{id}\"\n\
-            export GIT_AUTHOR_NAME=\"{name}\"\n\
-            export GIT_AUTHOR_EMAIL=\"{email}\"\n\
-            export GIT_COMMITTER_NAME=\"{name}\"\n\
-            export GIT_COMMITTER_EMAIL=\"{email}\"\n\
-            export GIT_SSH_COMMAND=\"ssh -i {sshkey_path} -F /dev/null\"\n\
+            # generated by gus for user '{id}'; changes are overwritten on switch\n\
+            [user]\n\
+            \tname = {name}\n\
+            \temail = {email}\n\
+            [core]\n\
+            \tsshCommand = {ssh_command}\n\
             ",
             id = user.id,
-            name = user.name,
-            email = user.email,
-            sshkey_path = user
-                .get_sshkey_path(&self.config.default_sshkey_dir)
-                .to_string_lossy()
+            name = quote_git_config_value(&user.name),
+            email = quote_git_config_value(&user.email),
+            ssh_command =
+                quote_git_config_value(&self.get_ssh_command(user, user.ssh_verbose.unwrap_or(0))?),
         );
+        std::fs::write(&path, contents)
+            .with_context(|| format!("failed to write gitconfig: {}", path.display()))
+    }
+
+    /// Paths are quoted for the shell that git runs the command with.
+    fn get_ssh_command(&self, user: &User, verbosity: u8) -> Result<String> {
+        let key = if user.agent_backed {
+            String::new()
+        } else {
+            shell_single_quote(
+                &user
+                    .get_sshkey_path(&self.config.default_sshkey_dir)
+                    .to_string_lossy(),
+            )
+        };
+
+        let mut options = Vec::new();
+        if let Some(known_hosts) = self.get_known_hosts_path(user) {
+            options.push(format!(
+                "-o UserKnownHostsFile={}",
+                shell_single_quote(&known_hosts.to_string_lossy())
+            ));
+        }
+        if user.host_key_pin.is_some() {
+            options.push("-o StrictHostKeyChecking=yes -o CheckHostIP=yes".to_string());
+        }
+        if verbosity > 0 {
+            options.push(format!("-{}", "v".repeat(verbosity as usize)));
+        }
+        if self.config.per_user_ssh_agent && !user.agent_backed {
+            options.push(format!(
+                "-o IdentityAgent={}",
+                shell_single_quote(&self.get_ssh_agent_socket_path(user).to_string_lossy())
+            ));
+        }
+        let options = options.join(" ");
+
+        if let Some(template) = self.get_ssh_template(user) {
+            return Ok(template
+                .replace("{key}", &key)
+                .replace("{options}", &options));
+        }
+
+        let ssh = match &self.config.ssh_path {
+            Some(_) => shell_single_quote(&self.config.get_ssh_path()?.to_string_lossy()),
+            None => "ssh".to_string(),
+        };
+        let mut command = if user.agent_backed {
+            format!("{} -F /dev/null", ssh)
+        } else {
+            format!("{} -i {} -F /dev/null", ssh, key)
+        };
+        if !options.is_empty() {
+            command.push(' ');
+            command.push_str(&options);
+        }
+        Ok(command)
+    }
 
-        write_session_script(&script)?;
+    fn get_ssh_template(&self, user: &User) -> Option<&String> {
+        self.config
+            .ssh_command_templates
+            .get(user.ssh_template.as_ref()?)
+    }
 
+    fn validate_ssh_template(&self, user: &User) -> Result<()> {
+        if let Some(name) = &user.ssh_template {
+            ensure!(
+                self.config.ssh_command_templates.contains_key(name),
+                "ssh command template '{}' is not defined in ssh_command_templates",
+                name
+            );
+        }
         Ok(())
     }
 
+    fn get_pinned_known_hosts_path(&self, user: &User) -> PathBuf {
+        self.config.pinned_known_hosts_dir.join(&user.id)
+    }
+
+    /// The known_hosts file ssh is pointed at: the pinned one when the user has a pin.
+    fn get_known_hosts_path(&self, user: &User) -> Option<PathBuf> {
+        if user.host_key_pin.is_some() {
+            Some(self.get_pinned_known_hosts_path(user))
+        } else {
+            user.known_hosts.clone()
+        }
+    }
+
+    /// Writes a known_hosts file holding only the user's pinned host key; does nothing without a pin.
+    fn write_pinned_known_hosts(&self, user: &User) -> Result<()> {
+        let Some(pin) = &user.host_key_pin else {
+            return Ok(());
+        };
+        let path = self.get_pinned_known_hosts_path(user);
+        std::fs::create_dir_all(&self.config.pinned_known_hosts_dir).with_context(|| {
+            format!(
+                "failed to create pinned known_hosts directory: {}",
+                self.config.pinned_known_hosts_dir.display()
+            )
+        })?;
+        std::fs::write(&path, format!("{} {}\n", user.get_host(), pin.trim()))
+            .with_context(|| format!("failed to write pinned known_hosts: {}", path.display()))
+    }
+
+    fn get_ssh_agent_socket_path(&self, user: &User) -> PathBuf {
+        self.config.ssh_agent_dir.join(format!("{}.sock", user.id))
+    }
+
+    /// Returns a script starting the user's dedicated ssh-agent and loading the user's key into it.
+    /// Agent-backed users already have their own agent and get no script.
+    pub fn get_ssh_agent_script(&self, user: &User) -> Option<String> {
+        if user.agent_backed {
+            return None;
+        }
+
+        Some(format!(
+            "\
+            # {id}\n\
+            mkdir -p {agent_dir}\n\
+            SSH_AUTH_SOCK={socket} ssh-add -l >/dev/null 2>&1\n\
+            if [ $? -eq 2 ]; then\n\
+                rm -f {socket}\n\
+                ssh-agent -a {socket} >/dev/null\n\
+            fi\n\
+            SSH_AUTH_SOCK={socket} ssh-add -l >/dev/null 2>&1 || \
+            SSH_AUTH_SOCK={socket} ssh-add {sshkey_path}\n\
+            ",
+            id = user.id,
+            agent_dir = shell_single_quote(&self.config.ssh_agent_dir.to_string_lossy()),
+            socket = shell_single_quote(&self.get_ssh_agent_socket_path(user).to_string_lossy()),
+            sshkey_path = shell_single_quote(
+                &user
+                    .get_sshkey_path(&self.config.default_sshkey_dir)
+                    .to_string_lossy()
+            ),
+        ))
+    }
+
+    pub fn unset_user(&self) -> Result<()> {
+        let (base, count) = get_caller_git_config_counts();
+        let script = build_unset_script(Shell::of_caller(), base, count);
+        self.emit_session_script(&script, None)
+    }
+
+    fn emit_session_script(&self, script: &str, dir: Option<&Path>) -> Result<()> {
+        match self.config.session_mode {
+            SessionMode::File => write_session_script(script, dir),
+            SessionMode::Eval => {
+                print!("{}", script);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes `user`'s name and email into the git config `scope` ("local" or "global") of the
+    /// repository at `dir`. Returns the changed keys with their old and new values.
+    pub fn reconcile_git_config(
+        &self,
+        user: &User,
+        dir: &Path,
+        scope: &str,
+    ) -> Result<Vec<(String, Option<String>, String)>> {
+        let git = self.config.get_git_path()?;
+        let mut changes = Vec::new();
+        for (key, value) in [("user.name", &user.name), ("user.email", &user.email)] {
+            let old = get_git_config(&git, dir, scope, key)?;
+            if old.as_ref() == Some(value) {
+                continue;
+            }
+            set_git_config(&git, dir, scope, key, value)?;
+            changes.push((key.to_string(), old, value.clone()));
+        }
+        Ok(changes)
+    }
+
+    /// Adds a co-author, given as a user selector or a raw `Name <email>`, to the session.
+    pub fn add_coauthor(&self, coauthor: &str) -> Result<()> {
+        let coauthors = self.add_to_coauthors(get_coauthors(), coauthor)?;
+        self.emit_coauthors(&coauthors)
+    }
+
+    pub fn remove_coauthor(&self, coauthor: &str) -> Result<()> {
+        let coauthors = self.remove_from_coauthors(get_coauthors(), coauthor)?;
+        self.emit_coauthors(&coauthors)
+    }
+
+    fn add_to_coauthors(&self, mut coauthors: Vec<String>, coauthor: &str) -> Result<Vec<String>> {
+        let coauthor = self.resolve_coauthor(coauthor)?;
+        ensure!(
+            !coauthors.contains(&coauthor),
+            "'{}' is already a co-author",
+            coauthor
+        );
+        coauthors.push(coauthor);
+        Ok(coauthors)
+    }
+
+    fn remove_from_coauthors(
+        &self,
+        mut coauthors: Vec<String>,
+        coauthor: &str,
+    ) -> Result<Vec<String>> {
+        let coauthor = self.resolve_coauthor(coauthor)?;
+        ensure!(
+            coauthors.contains(&coauthor),
+            "'{}' is not a co-author",
+            coauthor
+        );
+        coauthors.retain(|other| *other != coauthor);
+        Ok(coauthors)
+    }
+
+    fn resolve_coauthor(&self, coauthor: &str) -> Result<String> {
+        if let Some((name, email)) = coauthor.split_once('<') {
+            let email = email.strip_suffix('>').unwrap_or("");
+            ensure!(
+                !name.trim().is_empty() && email.contains('@'),
+                "co-author must be a user or 'Name <email>': {}",
+                coauthor
+            );
+            return Ok(format!("{} <{}>", name.trim(), email.trim()));
+        }
+        let user = self.find_user(coauthor)?;
+        Ok(format!("{} <{}>", user.name, user.email))
+    }
+
+    fn emit_coauthors(&self, coauthors: &[String]) -> Result<()> {
+        let shell = Shell::of_caller();
+        let script = if coauthors.is_empty() {
+            shell.format_unset(COAUTHORS_KEY)
+        } else {
+            shell.format_export(COAUTHORS_KEY, &coauthors.join("\n"))
+        };
+        self.emit_session_script(&script, None)
+    }
+
     pub fn get_current_user(&self) -> Option<&User> {
-        self.users.get(env::var("GUS_USER_ID").unwrap().as_str())
+        self.users.get(env::var("GUS_USER_ID").ok()?.as_str())
     }
 
     pub fn list_users(&self) -> Vec<&User> {
@@ -104,33 +946,378 @@ impl GitUserSwitcher {
     }
 
     pub fn get_public_sshkey(&self, id: &str) -> Result<String> {
+        ensure!(self.users.exists(id), UserError::NotFound(id.to_string()));
+        let user = self.users.get(id).unwrap();
         ensure!(
-            self.users.exists(id),
-            "user with id '{}' does not exist",
+            !user.agent_backed,
+            "user '{}' has an agent-backed ssh key; get the public key from the agent",
             id
         );
-        let user = self.users.get(id).unwrap();
-        let sshkey_path = user
-            .get_sshkey_path(&self.config.default_sshkey_dir)
-            .with_extension("pub");
+        let sshkey_path =
+            get_public_key_path(&user.get_sshkey_path(&self.config.default_sshkey_dir));
         let contents = std::fs::read_to_string(&sshkey_path)
             .with_context(|| format!("failed to read ssh key: {}", sshkey_path.display()))?;
         Ok(contents)
     }
 
-    pub fn get_setup_script(&self) -> String {
-        write_session_script("").unwrap();
-
-        let app_name = get_app_name();
+    pub fn rotate_sshkey(&self, id: &str, sshkey_passphrase: &str) -> Result<String> {
+        self.ensure_writable()?;
+        ensure!(self.users.exists(id), UserError::NotFound(id.to_string()));
+        let user = self.users.get(id).unwrap();
+        ensure!(
+            !user.agent_backed,
+            "user '{}' has an agent-backed ssh key which gus cannot rotate",
+            id
+        );
+        ensure!(
+            user.primary_key().is_none(),
+            "user '{}' uses an external ssh key which gus does not manage",
+            id
+        );
+        self.replace_sshkey(user, sshkey_passphrase)?;
+        self.get_public_sshkey(id)
+    }
 
-        let force_use_gus_script = if self.config.force_use_gus {
-            format!(
-                "\
-            if [ -z \"$GUS_USER_ID\" ]; then\n\
-                echo Users:\n\
-                {app_name} list\n\
-                echo -n \"Enter user id: \"\n\
-                read user_id\n\
+    /// Checks everything `regenerate_sshkey` would refuse, so callers can fail before prompting.
+    pub fn check_regenerate_sshkey(&self, id: &str, force: bool) -> Result<()> {
+        self.ensure_writable()?;
+        ensure!(self.users.exists(id), UserError::NotFound(id.to_string()));
+        let user = self.users.get(id).unwrap();
+        ensure!(
+            !user.agent_backed,
+            "user '{}' has an agent-backed ssh key which gus cannot generate",
+            id
+        );
+        ensure!(
+            user.primary_key().is_none(),
+            "user '{}' uses an external ssh key which gus does not manage",
+            id
+        );
+        let sshkey_path = user.get_sshkey_path(&self.config.default_sshkey_dir);
+        ensure!(
+            force || !sshkey_path.exists(),
+            "ssh key already exists at path: {} (use --force to replace it)",
+            sshkey_path.display()
+        );
+        Ok(())
+    }
+
+    /// Generates a new key at the user's key path, for keys that were deleted or never created.
+    /// An existing key is only replaced with `force`, and is restored if generation fails.
+    pub fn regenerate_sshkey(
+        &self,
+        id: &str,
+        sshkey_passphrase: &str,
+        force: bool,
+    ) -> Result<String> {
+        self.check_regenerate_sshkey(id, force)?;
+        let user = self.users.get(id).unwrap();
+        let sshkey_path = user.get_sshkey_path(&self.config.default_sshkey_dir);
+        if sshkey_path.exists() {
+            self.replace_sshkey(user, sshkey_passphrase)?;
+        } else {
+            self.generate_user_sshkey(user, sshkey_passphrase, &sshkey_path)?;
+            #[cfg(feature = "keychain")]
+            if user.passphrase_keychain {
+                crate::keychain::store_passphrase(id, sshkey_passphrase)?;
+            }
+        }
+        self.get_public_sshkey(id)
+    }
+
+    /// Backs up the user's key, generates a new one and restores the backup if that fails.
+    fn replace_sshkey(&self, user: &User, sshkey_passphrase: &str) -> Result<()> {
+        let sshkey_path = user.get_sshkey_path(&self.config.default_sshkey_dir);
+        let backup_suffix = backup_ssh_key(&sshkey_path)?;
+        if let Err(err) = self.generate_user_sshkey(user, sshkey_passphrase, &sshkey_path) {
+            restore_ssh_key(&sshkey_path, &backup_suffix)?;
+            return Err(err);
+        }
+
+        #[cfg(feature = "keychain")]
+        if user.passphrase_keychain {
+            crate::keychain::store_passphrase(&user.id, sshkey_passphrase)?;
+        }
+        Ok(())
+    }
+
+    pub fn rename_sshkey(&mut self, id: &str, new_filename: &str) -> Result<()> {
+        self.ensure_writable()?;
+        ensure!(self.users.exists(id), UserError::NotFound(id.to_string()));
+        ensure!(
+            is_safe_filename(new_filename),
+            "invalid ssh key filename: '{}'",
+            new_filename
+        );
+
+        let user = self.users.get(id).unwrap();
+        ensure!(
+            !user.agent_backed,
+            "user '{}' has an agent-backed ssh key which has no key file",
+            id
+        );
+        let old_path = user.get_sshkey_path(&self.config.default_sshkey_dir);
+        let new_path = old_path.with_file_name(new_filename);
+        ensure!(old_path.exists(), KeyError::Missing(old_path.clone()));
+        for path in [&new_path, &get_public_key_path(&new_path)] {
+            ensure!(!path.exists(), "file already exists: {}", path.display());
+        }
+
+        std::fs::rename(&old_path, &new_path)
+            .with_context(|| format!("failed to rename ssh key: {}", old_path.display()))?;
+        let old_pub_path = get_public_key_path(&old_path);
+        if old_pub_path.exists() {
+            std::fs::rename(&old_pub_path, get_public_key_path(&new_path))
+                .with_context(|| format!("failed to rename ssh key: {}", old_pub_path.display()))?;
+        }
+
+        self.users.get_mut(id).unwrap().set_primary_key(new_path);
+        self.users.save(&self.config.users_file_path)?;
+        Ok(())
+    }
+
+    /// Moves the users file and the ssh key directory into `new_dir`.
+    /// The config is only updated once everything has been moved.
+    pub fn relocate_data(&mut self, new_dir: &Path) -> Result<()> {
+        self.ensure_writable()?;
+        ensure!(
+            !self.users_file_overridden,
+            "relocate-data moves the configured users file and cannot be used with --users-file"
+        );
+
+        let new_dir = absolute_path(new_dir);
+        let old_users_file_path = self.config.users_file_path.clone();
+        let old_sshkey_dir = self.config.default_sshkey_dir.clone();
+        let new_users_file_path = new_dir.join(old_users_file_path.file_name().unwrap());
+        let new_sshkey_dir = new_dir.join(old_sshkey_dir.file_name().unwrap());
+        for path in [&new_users_file_path, &new_sshkey_dir] {
+            ensure!(!path.exists(), "file already exists: {}", path.display());
+        }
+
+        std::fs::create_dir_all(&new_dir)
+            .with_context(|| format!("failed to create directory: {}", new_dir.display()))?;
+        if old_sshkey_dir.exists() {
+            std::fs::rename(&old_sshkey_dir, &new_sshkey_dir).with_context(|| {
+                format!(
+                    "failed to move ssh key directory to {} (moving across filesystems is not supported)",
+                    new_sshkey_dir.display()
+                )
+            })?;
+        }
+
+        for user in self.users.list_mut() {
+            user.relocate_sshkey_paths(&old_sshkey_dir, &new_sshkey_dir);
+        }
+        if let Err(err) = self.users.save(&new_users_file_path) {
+            if new_sshkey_dir.exists() {
+                std::fs::rename(&new_sshkey_dir, &old_sshkey_dir).with_context(|| {
+                    format!(
+                        "failed to move ssh key directory back to {}",
+                        old_sshkey_dir.display()
+                    )
+                })?;
+            }
+            return Err(err);
+        }
+
+        self.update_config(|config| {
+            config.users_file_path = new_users_file_path.clone();
+            config.default_sshkey_dir = new_sshkey_dir.clone();
+        })?;
+        self.config.users_file_path = new_users_file_path;
+        self.config.default_sshkey_dir = new_sshkey_dir;
+
+        if old_users_file_path.exists() {
+            std::fs::remove_file(&old_users_file_path).with_context(|| {
+                format!(
+                    "failed to remove old users file: {}",
+                    old_users_file_path.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Moves the default-named ssh keys into `new_dir` and makes it the default ssh key directory.
+    /// Returns the planned moves; nothing is touched when `dry_run` is set.
+    pub fn relocate_sshkeys(
+        &mut self,
+        new_dir: &Path,
+        dry_run: bool,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        self.ensure_writable()?;
+        let new_dir = absolute_path(new_dir);
+
+        let mut moves = Vec::new();
+        for user in self.users.list() {
+            if user.agent_backed || user.primary_key().is_some() {
+                continue;
+            }
+            let old_path = user.get_sshkey_path(&self.config.default_sshkey_dir);
+            let new_path = user.get_sshkey_path(&new_dir);
+            for (from, to) in [
+                (old_path.clone(), new_path.clone()),
+                (
+                    get_public_key_path(&old_path),
+                    get_public_key_path(&new_path),
+                ),
+            ] {
+                if from.exists() {
+                    ensure!(!to.exists(), "file already exists: {}", to.display());
+                    moves.push((from, to));
+                }
+            }
+        }
+        moves.sort();
+        if dry_run {
+            return Ok(moves);
+        }
+
+        std::fs::create_dir_all(&new_dir).with_context(|| {
+            format!("failed to create ssh key directory: {}", new_dir.display())
+        })?;
+        for (i, (from, to)) in moves.iter().enumerate() {
+            if let Err(err) = std::fs::rename(from, to) {
+                // put back what was already moved so the old config stays valid
+                for (from, to) in &moves[..i] {
+                    let _ = std::fs::rename(to, from);
+                }
+                return Err(err)
+                    .with_context(|| format!("failed to move ssh key: {}", from.display()));
+            }
+        }
+
+        self.update_config(|config| config.default_sshkey_dir = new_dir.clone())?;
+        self.config.default_sshkey_dir = new_dir;
+        Ok(moves)
+    }
+
+    pub fn verify_sshkey_pair(&self, id: &str) -> Result<bool> {
+        let pubkey = self.get_public_sshkey(id)?;
+        let user = self.users.get(id).unwrap();
+        let ssh_keygen = self.config.get_ssh_keygen_path()?;
+        let derived = timing::time("ssh-keygen -y", || {
+            derive_public_key(
+                &ssh_keygen,
+                &user.get_sshkey_path(&self.config.default_sshkey_dir),
+            )
+        })
+        .with_context(|| format!("failed to verify ssh key pair for user: {}", id))?;
+        Ok(is_same_public_key(&pubkey, &derived))
+    }
+
+    /// Whether the user's public key is registered to `account` on GitHub.
+    pub fn is_sshkey_on_github(&self, id: &str, account: &str) -> Result<bool> {
+        let pubkey = self.get_public_sshkey(id)?;
+        let keys =
+            timing::time("github api", || fetch_github_keys(account)).with_context(|| {
+                format!(
+                    "failed to fetch the ssh keys of GitHub account: {}",
+                    account
+                )
+            })?;
+        Ok(keys.iter().any(|key| is_same_public_key(key, &pubkey)))
+    }
+
+    pub fn add_sshkey_to_agent(&self, id: &str) -> Result<()> {
+        ensure!(self.users.exists(id), UserError::NotFound(id.to_string()));
+        let user = self.users.get(id).unwrap();
+        ensure!(
+            !user.agent_backed,
+            "user '{}' has an agent-backed ssh key which is already held by its agent",
+            id
+        );
+        timing::time("ssh-add", || {
+            add_to_agent(
+                &user.get_sshkey_path(&self.config.default_sshkey_dir),
+                user.passphrase_keychain.then_some(id),
+            )
+        })
+        .with_context(|| format!("failed to add ssh key to agent for user: {}", id))
+    }
+
+    pub fn test_connection(
+        &self,
+        id: &str,
+        host: Option<&str>,
+        batch_mode: bool,
+    ) -> Result<(bool, String)> {
+        ensure!(self.users.exists(id), UserError::NotFound(id.to_string()));
+        let user = self.users.get(id).unwrap();
+        let host = host.unwrap_or(user.get_host());
+        let sshkey_path = user.get_sshkey_path(&self.config.default_sshkey_dir);
+        let sshkey_path = (!user.agent_backed).then_some(&sshkey_path);
+        let ssh = self.config.get_ssh_path()?;
+        self.write_pinned_known_hosts(user)?;
+        let known_hosts = self.get_known_hosts_path(user);
+        timing::time("ssh connection test", || {
+            test_connection(&ssh, sshkey_path, known_hosts.as_ref(), host, batch_mode)
+        })
+        .with_context(|| format!("failed to test connection to {} for user: {}", host, id))
+    }
+
+    pub fn get_ssh_config(&self, id: &str) -> Result<String> {
+        ensure!(self.users.exists(id), UserError::NotFound(id.to_string()));
+        let user = self.users.get(id).unwrap();
+        let host = user.get_host();
+
+        let mut block = format!("Host {id}.{host}\n  HostName {host}\n  User git\n");
+        if !user.agent_backed {
+            block.push_str(&format!(
+                "  IdentityFile {}\n  IdentitiesOnly yes\n",
+                user.get_sshkey_path(&self.config.default_sshkey_dir)
+                    .to_string_lossy()
+            ));
+        }
+        if let Some(known_hosts) = self.get_known_hosts_path(user) {
+            block.push_str(&format!(
+                "  UserKnownHostsFile {}\n",
+                known_hosts.to_string_lossy()
+            ));
+        }
+        if user.host_key_pin.is_some() {
+            self.write_pinned_known_hosts(user)?;
+            block.push_str("  StrictHostKeyChecking yes\n  CheckHostIP yes\n");
+        }
+        Ok(block)
+    }
+
+    pub fn get_setup_script(&self, shell: Shell) -> String {
+        if self.config.session_mode == SessionMode::File {
+            write_session_script("", None).unwrap();
+        }
+
+        let app_name = get_app_name();
+        let quote = match shell {
+            Shell::Fish => fish_single_quote,
+            _ => shell_single_quote,
+        };
+        let git = match &self.config.git_path {
+            Some(path) => quote(&path.to_string_lossy()),
+            None => "command git".to_owned(),
+        };
+
+        if shell == Shell::Fish {
+            return get_setup_script(
+                shell,
+                self.config.session_mode,
+                &self.get_fish_git_function(&app_name, &git),
+            );
+        }
+
+        let force_use_gus_script = if self.config.force_use_gus {
+            let message = match &self.config.force_use_gus_message {
+                Some(message) => format!("echo {}\n", shell_single_quote(message)),
+                None => "".to_owned(),
+            };
+            format!(
+                "\
+            if [ -z \"$GUS_USER_ID\" ]; then\n\
+                {message}\
+                echo Users:\n\
+                {app_name} list\n\
+                echo -n \"Enter user id: \"\n\
+                read user_id\n\
                 {app_name} set \"$user_id\"\n\
                 status=$?\n\
                 if [ $status -ne 0 ]; then\n\
@@ -143,13 +1330,2067 @@ impl GitUserSwitcher {
             "".to_owned()
         };
 
-        get_setup_script(&format!(
-            "\
+        let force_use_gus_script = if self.config.force_use_exempt_patterns.is_empty()
+            || force_use_gus_script.is_empty()
+        {
+            force_use_gus_script
+        } else {
+            let patterns = self.get_exempt_case_patterns();
+            format!(
+                "\
+            case \"$PWD\" in\n\
+                {patterns}) ;;\n\
+                *)\n\
+                {force_use_gus_script}\
+                ;;\n\
+            esac\n\
+            "
+            )
+        };
+
+        get_setup_script(
+            shell,
+            self.config.session_mode,
+            &format!(
+                "\
             function git() {{\n\
                 {force_use_gus_script}\
-                command git \"$@\"\n\
+                {git} \"$@\"\n\
             }}\n\
             "
-        ))
+            ),
+        )
+    }
+
+    /// The exempt patterns as the pattern list of a POSIX `case` item.
+    fn get_exempt_case_patterns(&self) -> String {
+        self.config
+            .force_use_exempt_patterns
+            .iter()
+            .map(|pattern| shell_glob_escape(&expand_home(pattern)))
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+
+    /// The fish counterpart of the `git` wrapper in `get_setup_script`.
+    fn get_fish_git_function(&self, app_name: &str, git: &str) -> String {
+        let force_use_gus_script = if self.config.force_use_gus {
+            let message = match &self.config.force_use_gus_message {
+                Some(message) => format!("echo {}\n", fish_single_quote(message)),
+                None => "".to_owned(),
+            };
+            format!(
+                "\
+            if test -z \"$GUS_USER_ID\"\n\
+                {message}\
+                echo Users:\n\
+                {app_name} list\n\
+                echo -n \"Enter user id: \"\n\
+                read user_id\n\
+                {app_name} set \"$user_id\"\n\
+                or return $status\n\
+            end\n\
+            "
+            )
+        } else {
+            "".to_owned()
+        };
+
+        // fish matches `*` and `?` in quoted case patterns, but has no bracket expressions.
+        let force_use_gus_script = if self.config.force_use_exempt_patterns.is_empty()
+            || force_use_gus_script.is_empty()
+        {
+            force_use_gus_script
+        } else {
+            let patterns = self
+                .config
+                .force_use_exempt_patterns
+                .iter()
+                .map(|pattern| fish_single_quote(&expand_home(pattern)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            format!(
+                "\
+            switch \"$PWD\"\n\
+                case {patterns}\n\
+                case '*'\n\
+                {force_use_gus_script}\
+            end\n\
+            "
+            )
+        };
+
+        format!(
+            "\
+            function git\n\
+                {force_use_gus_script}\
+                {git} $argv\n\
+            end\n\
+            "
+        )
+    }
+}
+
+/// Converts between `https://host/path` and `git@host:path.git`; URLs in other forms are kept as is.
+/// So are URLs with a port, which only applies to the protocol they already use.
+fn convert_clone_url(url: &str, protocol: Protocol) -> String {
+    let (host, path) = if let Some(rest) = url.strip_prefix("https://") {
+        match rest.split_once('/') {
+            Some(parts) => parts,
+            None => return url.to_string(),
+        }
+    } else if let Some(rest) = url.strip_prefix("ssh://git@") {
+        match rest.split_once('/') {
+            Some(parts) => parts,
+            None => return url.to_string(),
+        }
+    } else if let Some(rest) = url.strip_prefix("git@") {
+        match rest.split_once(':') {
+            Some(parts) => parts,
+            None => return url.to_string(),
+        }
+    } else {
+        return url.to_string();
+    };
+
+    let path = path.trim_end_matches('/');
+    let path = path.strip_suffix(".git").unwrap_or(path);
+    if path.is_empty() || host.contains(':') {
+        return url.to_string();
+    }
+    match protocol {
+        Protocol::Ssh => format!("git@{}:{}.git", host, path),
+        Protocol::Https => format!("https://{}/{}.git", host, path),
+    }
+}
+
+/// Whether the repository in the current directory has staged changes; false outside a repository.
+fn has_staged_changes(git: &Path, dir: &Path) -> bool {
+    Command::new(git)
+        .args(["diff", "--cached", "--quiet"])
+        .current_dir(dir)
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.code() == Some(1))
+        .unwrap_or(false)
+}
+
+fn is_inside_work_tree(git: &Path, dir: &Path) -> bool {
+    Command::new(git)
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .current_dir(dir)
+        .output()
+        .map(|output| output.status.success() && output.stdout.starts_with(b"true"))
+        .unwrap_or(false)
+}
+
+fn get_git_config(git: &Path, dir: &Path, scope: &str, key: &str) -> Result<Option<String>> {
+    let output = Command::new(git)
+        .args(["config", &format!("--{}", scope), "--get", key])
+        .current_dir(dir)
+        .output()
+        .context("failed to run git")?;
+    // exit code 1 means the key is not set
+    match output.status.code() {
+        Some(0) => Ok(Some(
+            String::from_utf8_lossy(&output.stdout)
+                .trim_end()
+                .to_string(),
+        )),
+        Some(1) => Ok(None),
+        _ => bail!(
+            "failed to read git config {}: {}",
+            key,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        ),
+    }
+}
+
+fn set_git_config(git: &Path, dir: &Path, scope: &str, key: &str, value: &str) -> Result<()> {
+    let output = Command::new(git)
+        .args(["config", &format!("--{}", scope), key, value])
+        .current_dir(dir)
+        .output()
+        .context("failed to run git")?;
+    ensure!(
+        output.status.success(),
+        "failed to write git config {}: {}",
+        key,
+        String::from_utf8_lossy(&output.stderr).trim_end()
+    );
+    Ok(())
+}
+
+/// The caller's GIT_CONFIG_* entries as (the user's own, the ones gus exported after them).
+fn get_caller_git_config_counts() -> (usize, usize) {
+    let parse = |key: &str| {
+        std::env::var(key)
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(0)
+    };
+    let count = parse("GIT_CONFIG_COUNT");
+    let gus_count = parse(GUS_GIT_CONFIG_COUNT_KEY).min(count);
+    (count - gus_count, gus_count)
+}
+
+/// Exports `git_config` after the `base` entries the user set up themselves.
+fn get_git_config_env(git_config: Vec<(String, String)>, base: usize) -> Vec<(String, String)> {
+    if git_config.is_empty() {
+        return Vec::new();
+    }
+    let mut env = vec![
+        (
+            "GIT_CONFIG_COUNT".to_string(),
+            (base + git_config.len()).to_string(),
+        ),
+        (
+            GUS_GIT_CONFIG_COUNT_KEY.to_string(),
+            git_config.len().to_string(),
+        ),
+    ];
+    for (i, (key, value)) in git_config.into_iter().enumerate() {
+        env.push((format!("GIT_CONFIG_KEY_{}", base + i), key));
+        env.push((format!("GIT_CONFIG_VALUE_{}", base + i), value));
+    }
+    env
+}
+
+/// Removes the entries gus exported beyond the first `keep`, leaving the user's own `base` entries.
+fn format_git_config_cleanup(shell: Shell, base: usize, count: usize, keep: usize) -> String {
+    let mut script = String::new();
+    for i in base + keep..base + count {
+        script.push_str(&shell.format_unset(&format!("GIT_CONFIG_KEY_{}", i)));
+        script.push_str(&shell.format_unset(&format!("GIT_CONFIG_VALUE_{}", i)));
+    }
+    if keep == 0 && count > 0 {
+        script.push_str(&shell.format_unset(GUS_GIT_CONFIG_COUNT_KEY));
+        if base == 0 {
+            script.push_str(&shell.format_unset("GIT_CONFIG_COUNT"));
+        } else {
+            script.push_str(&shell.format_export("GIT_CONFIG_COUNT", &base.to_string()));
+        }
+    }
+    script
+}
+
+/// Clears the session identity and the `count` git config entries gus exported after `base`.
+fn build_unset_script(shell: Shell, base: usize, count: usize) -> String {
+    let mut script = SESSION_ENV_KEYS
+        .iter()
+        .map(|key| shell.format_unset(key))
+        .collect::<String>();
+    script.push_str(&format_git_config_cleanup(shell, base, count, 0));
+    script
+}
+
+fn format_exports(shell: Shell, env: &[(String, String)]) -> String {
+    env.iter()
+        .map(|(key, value)| shell.format_export(key, value))
+        .collect()
+}
+
+pub fn get_coauthors() -> Vec<String> {
+    env::var(COAUTHORS_KEY)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// A prepare-commit-msg hook adding a Co-authored-by trailer for each co-author of the session.
+pub fn get_coauthor_hook() -> String {
+    format!(
+        "\
+        #!/bin/sh\n\
+        # generated by gus; adds the co-authors set with `gus coauthor add`\n\
+        [ -n \"${key}\" ] || exit 0\n\
+        case \"$2\" in\n\
+            merge|squash) exit 0 ;;\n\
+        esac\n\
+        printf '%s\\n' \"${key}\" | while IFS= read -r coauthor; do\n\
+            git interpret-trailers --in-place --if-exists addIfDifferent \\\n\
+                --trailer \"Co-authored-by: $coauthor\" \"$1\"\n\
+        done\n\
+        ",
+        key = COAUTHORS_KEY,
+    )
+}
+
+fn validate_host_key_pin(pin: &str) -> Result<()> {
+    const HOST_KEY_TYPES: [&str; 7] = [
+        "ssh-ed25519",
+        "ssh-rsa",
+        "ecdsa-sha2-nistp256",
+        "ecdsa-sha2-nistp384",
+        "ecdsa-sha2-nistp521",
+        "sk-ssh-ed25519@openssh.com",
+        "sk-ecdsa-sha2-nistp256@openssh.com",
+    ];
+    let fields = pin.split_whitespace().collect::<Vec<_>>();
+    ensure!(
+        fields.len() == 2 && HOST_KEY_TYPES.contains(&fields[0]),
+        "host key pin must be '<type> <base64>' with a known key type: {}",
+        pin
+    );
+    Ok(())
+}
+
+fn expand_home(pattern: &str) -> String {
+    match pattern.strip_prefix("~/") {
+        Some(rest) => format!("{}/{}", dirs::home_dir().unwrap().display(), rest),
+        None => pattern.to_string(),
+    }
+}
+
+fn read_activate_script(path: &PathBuf) -> Result<String> {
+    ensure!(
+        path.is_file(),
+        "activate script does not exist: {}",
+        path.display()
+    );
+
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("failed to read activate script: {}", path.display()))?;
+    if metadata.permissions().mode() & 0o002 != 0 {
+        eprintln!(
+            "warning: activate script is world-writable: {}",
+            path.display()
+        );
+    }
+
+    std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read activate script: {}", path.display()))
+}
+
+fn quote_git_config_value(value: &str) -> String {
+    let escaped = value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\t', "\\t");
+    format!("\"{}\"", escaped)
+}
+
+fn is_safe_filename(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shell::get_session_script_path;
+
+    /// A switcher whose config keeps every file inside `dir`.
+    fn switcher(dir: &Path) -> GitUserSwitcher {
+        let config = Config {
+            users_file_path: dir.join("users.toml"),
+            default_sshkey_dir: dir.join("sshkeys"),
+            gitconfig_dir: dir.join("gitconfigs"),
+            ssh_agent_dir: dir.join("agents"),
+            pinned_known_hosts_dir: dir.join("known_hosts"),
+            ..Config::default()
+        };
+        GitUserSwitcher {
+            users: Users::new(),
+            config,
+            config_path: Some(dir.join("config.toml")),
+            read_only: false,
+            users_file_overridden: false,
+        }
+    }
+
+    fn user(id: &str) -> User {
+        User {
+            id: id.to_string(),
+            name: format!("{} name", id),
+            email: format!("{}@example.com", id),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn session_script_is_inert_for_unsafe_user_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let gus = switcher(dir.path());
+        let mut bad = user("bad");
+        bad.name = "Bad\"; rm -rf ~ #".to_string();
+        bad.email = "`touch pwned`$(touch pwned)@example.com".to_string();
+
+        // HOME points at the scratch directory, so a broken script could only remove that
+        let canary = dir.path().join("canary");
+        std::fs::write(&canary, "").unwrap();
+
+        let script = gus
+            .build_session_script(&bad, &SwitchOptions::default())
+            .unwrap();
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(format!(
+                "{}printf '%s\\n%s' \"$GIT_AUTHOR_NAME\" \"$GIT_COMMITTER_EMAIL\"",
+                script
+            ))
+            .current_dir(dir.path())
+            .env("HOME", dir.path())
+            .output()
+            .unwrap();
+
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("{}\n{}", bad.name, bad.email)
+        );
+        assert!(canary.exists());
+        assert!(!dir.path().join("pwned").exists());
+    }
+
+    #[test]
+    fn edit_to_another_users_sshkey_needs_allow_shared_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("a")).unwrap();
+        gus.users.add(user("b")).unwrap();
+        write_key_pair(&dir.path().join("sshkeys/id_a"));
+        write_key_pair(&dir.path().join("sshkeys/id_other"));
+        let edit = |name: &str| UserEdit {
+            sshkey_path: Some(dir.path().join("sshkeys").join(name)),
+            ..Default::default()
+        };
+
+        let err = gus.edit_user("b", edit("id_a"), false, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("user 'a' already uses this ssh key"));
+        assert!(gus.users.get("b").unwrap().primary_key().is_none());
+
+        gus.edit_user("b", edit("id_other"), false, false).unwrap();
+        gus.edit_user("b", edit("id_a"), false, true).unwrap();
+        assert_eq!(
+            gus.users.get("b").unwrap().primary_key(),
+            Some(dir.path().join("sshkeys/id_a").as_path())
+        );
+    }
+
+    #[test]
+    fn email_domain_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.allowed_email_domains = vec!["corp.example".to_string()];
+
+        gus.check_email_domain("me@corp.example", false).unwrap();
+        gus.check_email_domain("me@CORP.example", false).unwrap();
+        let err = gus.check_email_domain("me@gmail.com", false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("email domain 'gmail.com' is not allowed"));
+        gus.check_email_domain("me@gmail.com", true).unwrap();
+
+        gus.config.allowed_email_domains.clear();
+        gus.check_email_domain("me@gmail.com", false).unwrap();
+    }
+
+    #[test]
+    fn dumped_script_matches_written_session_script() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("work")).unwrap();
+        let options = SwitchOptions {
+            temp_dir: Some(dir.path().join("sessions")),
+            ..Default::default()
+        };
+
+        gus.switch_user("work", &options).unwrap();
+        let written =
+            std::fs::read_to_string(get_session_script_path(options.temp_dir.as_deref())).unwrap();
+        assert_eq!(gus.get_session_script("work", &options).unwrap(), written);
+        assert!(written.contains("export GIT_AUTHOR_EMAIL='work@example.com'\n"));
+    }
+
+    fn write_key_pair(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "private").unwrap();
+        std::fs::write(get_public_key_path(path), "public").unwrap();
+    }
+
+    #[test]
+    fn rename_sshkey_moves_both_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("john.doe")).unwrap();
+        let old_path = dir.path().join("sshkeys/id_john.doe");
+        write_key_pair(&old_path);
+        let neighbour = dir.path().join("sshkeys/id_john.pub");
+        std::fs::write(&neighbour, "john's key").unwrap();
+
+        gus.rename_sshkey("john.doe", "id_work.old").unwrap();
+
+        let new_path = dir.path().join("sshkeys/id_work.old");
+        assert!(!old_path.exists());
+        assert!(!get_public_key_path(&old_path).exists());
+        assert_eq!(std::fs::read_to_string(&new_path).unwrap(), "private");
+        assert_eq!(
+            std::fs::read_to_string(get_public_key_path(&new_path)).unwrap(),
+            "public"
+        );
+        assert_eq!(std::fs::read_to_string(&neighbour).unwrap(), "john's key");
+        assert_eq!(
+            gus.users.get("john.doe").unwrap().primary_key(),
+            Some(new_path.as_path())
+        );
+        let saved = Users::open(&gus.config.users_file_path).unwrap();
+        assert_eq!(
+            saved.get("john.doe").unwrap().primary_key(),
+            Some(new_path.as_path())
+        );
+    }
+
+    #[test]
+    fn relocate_sshkeys_moves_both_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("john.doe")).unwrap();
+        let old_path = dir.path().join("sshkeys/id_john.doe");
+        write_key_pair(&old_path);
+        let new_dir = dir.path().join("moved");
+
+        let planned = gus.relocate_sshkeys(&new_dir, true).unwrap();
+        assert_eq!(planned.len(), 2);
+        assert!(old_path.exists());
+
+        gus.relocate_sshkeys(&new_dir, false).unwrap();
+        let new_path = new_dir.join("id_john.doe");
+        assert!(!old_path.exists());
+        assert!(!get_public_key_path(&old_path).exists());
+        assert!(new_path.exists());
+        assert!(get_public_key_path(&new_path).exists());
+        assert_eq!(gus.config.default_sshkey_dir, new_dir);
+        assert_eq!(
+            gus.users
+                .get("john.doe")
+                .unwrap()
+                .get_sshkey_path(&gus.config.default_sshkey_dir),
+            new_path
+        );
+    }
+
+    fn git_config_env(env: &[(String, String)]) -> Vec<(String, String)> {
+        let lookup = |key: &str| env.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone());
+        let count: usize = lookup("GIT_CONFIG_COUNT").map_or(0, |n| n.parse().unwrap());
+        let gus_count: usize = lookup(GUS_GIT_CONFIG_COUNT_KEY).map_or(0, |n| n.parse().unwrap());
+        (count - gus_count..count)
+            .map(|i| {
+                (
+                    lookup(&format!("GIT_CONFIG_KEY_{}", i)).unwrap(),
+                    lookup(&format!("GIT_CONFIG_VALUE_{}", i)).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ssh_signing_exports_public_key_and_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let gus = switcher(dir.path());
+        let mut signer = user("john.doe");
+        signer.sign_format = Some(SignFormat::Ssh);
+
+        let config = git_config_env(&gus.build_env(&signer, &SwitchOptions::default()).unwrap());
+        let pubkey = dir.path().join("sshkeys/id_john.doe.pub");
+        assert!(config.contains(&("commit.gpgsign".to_string(), "true".to_string())));
+        assert!(config.contains(&(
+            "user.signingkey".to_string(),
+            pubkey.to_string_lossy().to_string()
+        )));
+        assert!(config.contains(&("gpg.format".to_string(), "ssh".to_string())));
+        assert!(!config.iter().any(|(key, _)| key == "tag.gpgsign"));
+    }
+
+    #[test]
+    fn signing_key_only_signs_commits_when_sign_commits_is_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        let mut signer = user("a");
+        signer.signing_key = Some("ABCDEF".to_string());
+
+        gus.config.sign_commits = false;
+        let config = git_config_env(&gus.build_env(&signer, &SwitchOptions::default()).unwrap());
+        assert!(config.contains(&("user.signingkey".to_string(), "ABCDEF".to_string())));
+        assert!(!config.iter().any(|(key, _)| key == "commit.gpgsign"));
+
+        signer.require_signing = true;
+        let config = git_config_env(&gus.build_env(&signer, &SwitchOptions::default()).unwrap());
+        assert!(config.contains(&("commit.gpgsign".to_string(), "true".to_string())));
+        assert!(config.contains(&("tag.gpgsign".to_string(), "true".to_string())));
+    }
+
+    #[test]
+    fn edit_signing_key_and_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("a")).unwrap();
+
+        gus.edit_user(
+            "a",
+            UserEdit {
+                signing_key: Some("ABCDEF".to_string()),
+                sign_format: Some(SignFormat::Openpgp),
+                ..Default::default()
+            },
+            false,
+            false,
+        )
+        .unwrap();
+
+        let saved = Users::open(&gus.config.users_file_path).unwrap();
+        let edited = saved.get("a").unwrap();
+        assert_eq!(edited.signing_key.as_deref(), Some("ABCDEF"));
+        assert_eq!(edited.sign_format, Some(SignFormat::Openpgp));
+        assert!(gus
+            .edit_user("a", UserEdit::default(), false, false)
+            .is_err());
+    }
+
+    #[test]
+    fn regenerate_sshkey_checks_run_before_any_prompt() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("a")).unwrap();
+        let mut external = user("external");
+        external.set_primary_key(dir.path().join("elsewhere/id_work"));
+        gus.users.add(external).unwrap();
+
+        gus.check_regenerate_sshkey("a", false).unwrap();
+        write_key_pair(&dir.path().join("sshkeys/id_a"));
+        let err = gus.check_regenerate_sshkey("a", false).unwrap_err();
+        assert!(err.to_string().contains("use --force"));
+        gus.check_regenerate_sshkey("a", true).unwrap();
+
+        let err = gus.check_regenerate_sshkey("external", true).unwrap_err();
+        assert!(err.to_string().contains("external ssh key"));
+        let err = gus
+            .regenerate_sshkey("external", "passphrase1234", true)
+            .unwrap_err();
+        assert!(err.to_string().contains("external ssh key"));
+        assert!(gus.check_regenerate_sshkey("missing", false).is_err());
+    }
+
+    #[test]
+    fn add_user_applies_email_and_shared_key_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.allowed_email_domains = vec!["example.com".to_string()];
+        let sshkey_path = dir.path().join("keys/id_work");
+        write_key_pair(&sshkey_path);
+
+        let mut a = user("a");
+        a.set_primary_key(sshkey_path.clone());
+        gus.add_user(a, None, false, false).unwrap();
+
+        let mut b = user("b");
+        b.set_primary_key(sshkey_path.clone());
+        let err = gus.add_user(b.clone(), None, false, false).unwrap_err();
+        assert!(err.to_string().contains("already uses this ssh key"));
+        assert!(!gus.users.exists("b"));
+
+        b.email = "b@other.example".to_string();
+        let err = gus.add_user(b.clone(), None, false, true).unwrap_err();
+        assert!(err.to_string().contains("is not allowed"));
+        assert!(!gus.users.exists("b"));
+
+        gus.add_user(b, None, true, true).unwrap();
+        let saved = Users::open(&gus.config.users_file_path).unwrap();
+        assert!(saved.exists("a") && saved.exists("b"));
+    }
+
+    #[test]
+    fn edit_email_outside_allowed_domains_needs_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("a")).unwrap();
+        gus.config.allowed_email_domains = vec!["example.com".to_string()];
+        let edit = || UserEdit {
+            email: Some("a@gmail.com".to_string()),
+            ..Default::default()
+        };
+
+        let err = gus.edit_user("a", edit(), false, false).unwrap_err();
+        assert!(err.to_string().contains("is not allowed"));
+        assert_eq!(gus.users.get("a").unwrap().email, "a@example.com");
+
+        gus.edit_user("a", edit(), true, false).unwrap();
+        assert_eq!(gus.users.get("a").unwrap().email, "a@gmail.com");
+    }
+
+    #[test]
+    fn rename_user_renames_files_derived_from_the_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("old")).unwrap();
+        write_key_pair(&dir.path().join("sshkeys/id_old"));
+        for path in [
+            "known_hosts/old",
+            "agents/old.sock",
+            "gitconfigs/old.gitconfig",
+        ] {
+            std::fs::create_dir_all(dir.path().join(path).parent().unwrap()).unwrap();
+            std::fs::write(dir.path().join(path), path).unwrap();
+        }
+
+        gus.rename_user("old", "new").unwrap();
+
+        let renamed = gus.users.get("new").unwrap();
+        assert!(renamed.primary_key().is_none());
+        assert_eq!(
+            renamed.get_sshkey_path(&gus.config.default_sshkey_dir),
+            dir.path().join("sshkeys/id_new")
+        );
+        for (old, new) in [
+            ("sshkeys/id_old", "sshkeys/id_new"),
+            ("sshkeys/id_old.pub", "sshkeys/id_new.pub"),
+            ("known_hosts/old", "known_hosts/new"),
+            ("agents/old.sock", "agents/new.sock"),
+        ] {
+            assert!(!dir.path().join(old).exists(), "{} still exists", old);
+            assert!(dir.path().join(new).exists(), "{} is missing", new);
+        }
+        assert!(!dir.path().join("gitconfigs/old.gitconfig").exists());
+        let saved = Users::open(&gus.config.users_file_path).unwrap();
+        assert!(!saved.exists("old") && saved.exists("new"));
+    }
+
+    #[test]
+    fn rename_user_refuses_to_overwrite_a_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("old")).unwrap();
+        write_key_pair(&dir.path().join("sshkeys/id_old"));
+        write_key_pair(&dir.path().join("sshkeys/id_new"));
+
+        assert!(gus.rename_user("old", "new").is_err());
+        assert!(gus.users.exists("old"));
+        assert!(dir.path().join("sshkeys/id_old.pub").exists());
+    }
+
+    #[test]
+    fn default_key_type_must_meet_an_allowed_minimum() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.allowed_sshkey_types = vec![SshKeyType::Rsa { bits: 4096 }, SshKeyType::Ed25519];
+
+        gus.config.default_sshkey_type = SshKeyType::Rsa { bits: 8192 };
+        gus.get_sshkey_type().unwrap();
+        gus.config.default_sshkey_type = SshKeyType::Ed25519;
+        gus.get_sshkey_type().unwrap();
+        gus.config.default_sshkey_type = SshKeyType::Rsa { bits: 3072 };
+        assert!(gus.get_sshkey_type().is_err());
+        gus.config.default_sshkey_type = SshKeyType::Ecdsa { bits: 521 };
+        assert!(gus.get_sshkey_type().is_err());
+    }
+
+    #[test]
+    fn temp_dir_session_does_not_leave_an_older_session_to_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("work")).unwrap();
+        let options = SwitchOptions {
+            temp_dir: Some(dir.path().join("sessions")),
+            ..Default::default()
+        };
+        let default_path = get_session_script_path(None);
+        std::fs::create_dir_all(default_path.parent().unwrap()).unwrap();
+        std::fs::write(&default_path, "export GIT_AUTHOR_NAME='stale'\n").unwrap();
+
+        gus.switch_user("work", &options).unwrap();
+
+        // what the wrapper does after gus returns, followed by sourcing the temp-dir script
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(
+                "[ -f \"$1\" ] && . \"$1\"; echo \"wrapper:$GIT_AUTHOR_NAME\"; \
+                 . \"$2\"; echo \"sourced:$GIT_AUTHOR_NAME\"",
+            )
+            .arg("bash")
+            .arg(&default_path)
+            .arg(get_session_script_path(options.temp_dir.as_deref()))
+            .env_clear()
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "wrapper:\nsourced:work name\n"
+        );
+    }
+
+    #[test]
+    fn core_errors_keep_their_type() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("a")).unwrap();
+
+        let err = gus.get_public_sshkey("missing").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<UserError>(),
+            Some(UserError::NotFound(id)) if id == "missing"
+        ));
+        let err = gus.rename_sshkey("a", "id_b").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<KeyError>(),
+            Some(KeyError::Missing(_))
+        ));
+        let err = gus.find_user("nobody").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<UserError>(),
+            Some(UserError::NoMatch(_))
+        ));
+        let err = gus.add_user(user("a"), None, false, false).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<UserError>(),
+            Some(UserError::AlreadyExists(_))
+        ));
+    }
+
+    /// Writes an executable script at `path` that prints its arguments one per line.
+    fn write_echo_tool(path: &Path, name: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(
+            path,
+            format!(
+                "#!/bin/sh\necho {}\nfor arg; do echo \"$arg\"; done\n",
+                name
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn ssh_command_quotes_every_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let tricky = dir.path().join("it's $HOME");
+        let mut gus = switcher(&tricky);
+        gus.config.per_user_ssh_agent = true;
+        gus.config.ssh_path = Some(tricky.join("bin/ssh"));
+        write_echo_tool(gus.config.ssh_path.as_ref().unwrap(), "ssh");
+        let mut work = user("work");
+        work.known_hosts = Some(tricky.join("known hosts"));
+
+        let command = gus.get_ssh_command(&work, 0).unwrap();
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} git@github.com", command))
+            .output()
+            .unwrap();
+        let sshkey = tricky.join("sshkeys/id_work");
+        let agent = tricky.join("agents/work.sock");
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!(
+                "ssh\n-i\n{}\n-F\n/dev/null\n-o\nUserKnownHostsFile={}\n-o\nIdentityAgent={}\ngit@github.com\n",
+                sshkey.display(),
+                tricky.join("known hosts").display(),
+                agent.display()
+            )
+        );
+
+        gus.config.ssh_path = Some(tricky.join("bin/missing"));
+        let err = gus.get_ssh_command(&work, 0).unwrap_err();
+        assert!(err.to_string().contains("ssh_path does not exist"));
+    }
+
+    #[test]
+    fn setup_git_function_runs_the_configured_git() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.session_mode = SessionMode::Eval;
+        gus.config.force_use_gus = false;
+        gus.config.git_path = Some(dir.path().join("it's $HOME/git"));
+        write_echo_tool(gus.config.git_path.as_ref().unwrap(), "configured git");
+
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(format!("{}git status", gus.get_setup_script(Shell::Bash)))
+            .env_remove("GUS_LOADED_FLAG")
+            .stdin(std::process::Stdio::null())
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "configured git\nstatus\n"
+        );
+    }
+
+    #[test]
+    fn autocrlf_and_eol_values() {
+        use crate::user::{AutoCrlf, Eol};
+        use clap::ValueEnum;
+
+        let users: Users = toml::from_str(
+            "[work]\nid = \"work\"\nname = \"w\"\nemail = \"w@example.com\"\n\
+             autocrlf = \"input\"\neol = \"crlf\"\n",
+        )
+        .unwrap();
+        let work = users.get("work").unwrap();
+        let config = git_config_env(&gus_env_for(work));
+        assert!(config.contains(&("core.autocrlf".to_string(), "input".to_string())));
+        assert!(config.contains(&("core.eol".to_string(), "crlf".to_string())));
+
+        for bad in ["autocrlf = \"yes\"", "eol = \"cr\""] {
+            let toml = format!("[work]\nid = \"work\"\n{}\n", bad);
+            assert!(toml::from_str::<Users>(&toml).is_err(), "{} parsed", bad);
+        }
+        assert!(AutoCrlf::from_str("true", false).is_ok());
+        assert!(AutoCrlf::from_str("yes", false).is_err());
+        assert!(Eol::from_str("native", false).is_ok());
+        assert!(Eol::from_str("cr", false).is_err());
+    }
+
+    fn gus_env_for(user: &User) -> Vec<(String, String)> {
+        let dir = tempfile::tempdir().unwrap();
+        switcher(dir.path())
+            .build_env(user, &SwitchOptions::default())
+            .unwrap()
+    }
+
+    #[test]
+    fn git_config_exports_keep_the_users_own_entries() {
+        let git_config = vec![
+            ("core.autocrlf".to_string(), "input".to_string()),
+            ("core.eol".to_string(), "lf".to_string()),
+        ];
+        let env = get_git_config_env(git_config, 1);
+        assert_eq!(
+            env,
+            [
+                ("GIT_CONFIG_COUNT", "3"),
+                ("GUS_GIT_CONFIG_COUNT", "2"),
+                ("GIT_CONFIG_KEY_1", "core.autocrlf"),
+                ("GIT_CONFIG_VALUE_1", "input"),
+                ("GIT_CONFIG_KEY_2", "core.eol"),
+                ("GIT_CONFIG_VALUE_2", "lf"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+
+        // git sees both the user's entry and gus's, and only the user's once gus cleans up
+        let script = format!(
+            "export GIT_CONFIG_COUNT=1 GIT_CONFIG_KEY_0=user.own GIT_CONFIG_VALUE_0=kept\n\
+             {exports}\
+             git config --get user.own; git config --get core.eol\n\
+             {cleanup}\
+             git config --get user.own; git config --get core.eol || echo no eol\n\
+             echo \"$GIT_CONFIG_COUNT ${{GUS_GIT_CONFIG_COUNT-unset}} ${{GIT_CONFIG_KEY_1-unset}}\"\n",
+            exports = format_exports(Shell::Bash, &env),
+            cleanup = format_git_config_cleanup(Shell::Bash, 1, 2, 0),
+        );
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(script)
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("HOME", tempfile::tempdir().unwrap().path())
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "kept\nlf\nkept\nno eol\n1 unset unset\n"
+        );
+    }
+
+    #[test]
+    fn git_config_cleanup_only_touches_what_gus_exported() {
+        // switching to a user with fewer entries drops the extra ones
+        assert_eq!(
+            format_git_config_cleanup(Shell::Bash, 0, 3, 1),
+            "unset GIT_CONFIG_KEY_1\nunset GIT_CONFIG_VALUE_1\n\
+             unset GIT_CONFIG_KEY_2\nunset GIT_CONFIG_VALUE_2\n"
+        );
+        // without gus entries the user's own GIT_CONFIG_COUNT is left alone
+        assert_eq!(format_git_config_cleanup(Shell::Bash, 2, 0, 0), "");
+        assert_eq!(
+            format_git_config_cleanup(Shell::Bash, 0, 1, 0),
+            "unset GIT_CONFIG_KEY_0\nunset GIT_CONFIG_VALUE_0\n\
+             unset GUS_GIT_CONFIG_COUNT\nunset GIT_CONFIG_COUNT\n"
+        );
+    }
+
+    #[test]
+    fn convert_clone_urls() {
+        let cases = [
+            (
+                "https://github.com/o/r",
+                "git@github.com:o/r.git",
+                "https://github.com/o/r.git",
+            ),
+            (
+                "https://github.com/o/r.git/",
+                "git@github.com:o/r.git",
+                "https://github.com/o/r.git",
+            ),
+            (
+                "git@github.com:o/r.git",
+                "git@github.com:o/r.git",
+                "https://github.com/o/r.git",
+            ),
+            (
+                "ssh://git@github.com/o/r.git",
+                "git@github.com:o/r.git",
+                "https://github.com/o/r.git",
+            ),
+            // a port belongs to the protocol the URL already uses
+            (
+                "ssh://git@git.corp:2222/o/r.git",
+                "ssh://git@git.corp:2222/o/r.git",
+                "ssh://git@git.corp:2222/o/r.git",
+            ),
+            (
+                "https://git.corp:8443/o/r.git",
+                "https://git.corp:8443/o/r.git",
+                "https://git.corp:8443/o/r.git",
+            ),
+            (
+                "file:///srv/r.git",
+                "file:///srv/r.git",
+                "file:///srv/r.git",
+            ),
+        ];
+        for (url, ssh, https) in cases {
+            assert_eq!(convert_clone_url(url, Protocol::Ssh), ssh, "{}", url);
+            assert_eq!(convert_clone_url(url, Protocol::Https), https, "{}", url);
+        }
+    }
+
+    #[test]
+    fn coauthor_list_management() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("pair")).unwrap();
+
+        assert_eq!(
+            gus.resolve_coauthor("pair").unwrap(),
+            "pair name <pair@example.com>"
+        );
+        assert_eq!(
+            gus.resolve_coauthor("  Jane Doe <jane@example.com>")
+                .unwrap(),
+            "Jane Doe <jane@example.com>"
+        );
+        assert!(gus.resolve_coauthor("Jane Doe <nope>").is_err());
+        assert!(gus.resolve_coauthor(" <jane@example.com>").is_err());
+        assert!(gus.resolve_coauthor("nobody").is_err());
+
+        let coauthors = gus.add_to_coauthors(Vec::new(), "pair").unwrap();
+        let coauthors = gus
+            .add_to_coauthors(coauthors, "Jane Doe <jane@example.com>")
+            .unwrap();
+        assert_eq!(
+            coauthors,
+            [
+                "pair name <pair@example.com>",
+                "Jane Doe <jane@example.com>"
+            ]
+        );
+        let err = gus
+            .add_to_coauthors(coauthors.clone(), "pair@example.com")
+            .unwrap_err();
+        assert!(err.to_string().contains("is already a co-author"));
+
+        let coauthors = gus.remove_from_coauthors(coauthors, "pair").unwrap();
+        assert_eq!(coauthors, ["Jane Doe <jane@example.com>"]);
+        let err = gus.remove_from_coauthors(coauthors, "pair").unwrap_err();
+        assert!(err.to_string().contains("is not a co-author"));
+    }
+
+    #[test]
+    fn coauthor_hook_adds_trailers_to_commits() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = |args: &[&str], coauthors: &str| {
+            let output = Command::new("git")
+                .args(["-c", "user.name=Me", "-c", "user.email=me@example.com"])
+                .args(args)
+                .current_dir(dir.path())
+                .env("HOME", dir.path())
+                .env("GIT_CONFIG_NOSYSTEM", "1")
+                .env(COAUTHORS_KEY, coauthors)
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            String::from_utf8(output.stdout).unwrap()
+        };
+        git(&["init", "-q"], "");
+        let hook = dir.path().join(".git/hooks/prepare-commit-msg");
+        std::fs::write(&hook, get_coauthor_hook()).unwrap();
+        std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let coauthors = "Jane Doe <jane@example.com>\nJohn Roe <john@example.com>";
+        git(
+            &["commit", "-q", "--allow-empty", "-m", "pair work"],
+            coauthors,
+        );
+        assert_eq!(
+            git(&["log", "-1", "--format=%B"], ""),
+            "pair work\n\n\
+             Co-authored-by: Jane Doe <jane@example.com>\n\
+             Co-authored-by: John Roe <john@example.com>\n\n"
+        );
+
+        git(&["commit", "-q", "--allow-empty", "-m", "solo work"], "");
+        assert_eq!(git(&["log", "-1", "--format=%B"], ""), "solo work\n\n");
+    }
+
+    /// Runs git in `dir` with no global or system config, failing the test if git fails.
+    fn run_git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args([
+                "-c",
+                "user.name=Fixture",
+                "-c",
+                "user.email=fixture@example.com",
+            ])
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "git {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn staged_changes_are_detected_in_the_given_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let git = Path::new("git");
+        assert!(!is_inside_work_tree(git, dir.path()));
+        assert!(!has_staged_changes(git, dir.path()));
+
+        run_git(dir.path(), &["init", "-q"]);
+        assert!(is_inside_work_tree(git, dir.path()));
+        std::fs::write(dir.path().join("file"), "one").unwrap();
+        assert!(!has_staged_changes(git, dir.path()));
+
+        run_git(dir.path(), &["add", "file"]);
+        assert!(has_staged_changes(git, dir.path()));
+        run_git(dir.path(), &["commit", "-q", "-m", "first"]);
+        assert!(!has_staged_changes(git, dir.path()));
+    }
+
+    #[test]
+    fn exempt_patterns_match_pwd_in_bash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        let root = dir.path().join("my (work) dirs");
+        gus.config.force_use_exempt_patterns = vec![
+            format!("{}/scratch*", root.display()),
+            format!("{}/tmp?", root.display()),
+        ];
+
+        let script = format!(
+            "case \"$PWD\" in\n{}) echo exempt ;;\n*) echo nag ;;\nesac\n",
+            gus.get_exempt_case_patterns()
+        );
+        for (name, expected) in [
+            ("scratch", "exempt"),
+            ("scratch-2", "exempt"),
+            ("tmp1", "exempt"),
+            ("tmp12", "nag"),
+            ("work", "nag"),
+        ] {
+            let cwd = root.join(name);
+            std::fs::create_dir_all(&cwd).unwrap();
+            let output = Command::new("bash")
+                .arg("-c")
+                .arg(&script)
+                .current_dir(&cwd)
+                .env("PWD", &cwd)
+                .output()
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(output.stdout).unwrap().trim_end(),
+                expected,
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn direnv_script_exports_the_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("work")).unwrap();
+
+        let script = gus.get_direnv_script("work@example.com").unwrap();
+        assert!(script.starts_with("# gus: work\n"));
+        assert!(script.contains("export GUS_USER_ID='work'\n"));
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(format!(
+                "{}echo \"$GUS_USER_ID|$GIT_AUTHOR_NAME|$GIT_COMMITTER_EMAIL\"",
+                script
+            ))
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "work|work name|work@example.com\n"
+        );
+        assert!(gus.get_direnv_script("nobody").is_err());
+    }
+
+    const PIN: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIOMqqnkVzrm0SdG6UOoqKLsabgH5C9okWi0dh2l9GKJl";
+
+    #[test]
+    fn host_key_pin_reaches_ssh() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        let mut pinned = user("pinned");
+        pinned.host_key_pin = Some(PIN.to_string());
+        gus.users.add(pinned.clone()).unwrap();
+        let known_hosts = dir.path().join("known_hosts/pinned");
+
+        let command = gus.get_ssh_command(&pinned, 0).unwrap();
+        assert!(command.contains(&format!(
+            "-o UserKnownHostsFile='{}' -o StrictHostKeyChecking=yes -o CheckHostIP=yes",
+            known_hosts.display()
+        )));
+
+        let block = gus.get_ssh_config("pinned").unwrap();
+        assert!(block.contains(&format!(
+            "  UserKnownHostsFile {}\n  StrictHostKeyChecking yes\n  CheckHostIP yes\n",
+            known_hosts.display()
+        )));
+        assert_eq!(
+            std::fs::read_to_string(&known_hosts).unwrap(),
+            format!("github.com {}\n", PIN)
+        );
+    }
+
+    #[test]
+    fn pinned_known_hosts_is_written_wherever_the_env_is_built() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        let mut pinned = user("pinned");
+        pinned.host_key_pin = Some(PIN.to_string());
+        gus.users.add(pinned.clone()).unwrap();
+        let known_hosts = dir.path().join("known_hosts/pinned");
+
+        // `env` and `current --json-env`
+        gus.build_env(&pinned, &SwitchOptions::default()).unwrap();
+        assert!(known_hosts.is_file());
+
+        std::fs::remove_file(&known_hosts).unwrap();
+        gus.get_direnv_script("pinned").unwrap();
+        assert!(known_hosts.is_file());
+    }
+
+    #[test]
+    fn switch_user_local_writes_the_repo_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        std::fs::create_dir(&repo).unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("alice")).unwrap();
+
+        assert!(gus.switch_user_local("alice", &repo, "local").is_err());
+
+        run_git(&repo, &["init", "-q"]);
+        let keys = gus.switch_user_local("alice", &repo, "local").unwrap();
+        assert_eq!(keys[..3], ["user.name", "user.email", "core.sshCommand"]);
+
+        let git = Path::new("git");
+        let get = |key| get_git_config(git, &repo, "local", key).unwrap();
+        assert_eq!(get("user.name").as_deref(), Some("alice name"));
+        assert_eq!(get("user.email").as_deref(), Some("alice@example.com"));
+        assert_eq!(
+            get("core.sshCommand"),
+            Some(
+                gus.get_ssh_command(gus.users.get("alice").unwrap(), 0)
+                    .unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn ssh_command_verbosity_takes_the_higher_of_option_and_user() {
+        let dir = tempfile::tempdir().unwrap();
+        let gus = switcher(dir.path());
+        let ssh_command = |user_verbose, option_verbose| {
+            let mut alice = user("alice");
+            alice.ssh_verbose = user_verbose;
+            let options = SwitchOptions {
+                ssh_verbose: option_verbose,
+                ..Default::default()
+            };
+            let env = gus.build_env(&alice, &options).unwrap();
+            env.into_iter()
+                .find(|(key, _)| key == "GIT_SSH_COMMAND")
+                .unwrap()
+                .1
+        };
+
+        assert!(!ssh_command(None, 0).contains(" -v"));
+        assert!(ssh_command(Some(2), 0).ends_with(" -vv"));
+        assert!(ssh_command(Some(1), 3).ends_with(" -vvv"));
+        assert!(ssh_command(Some(3), 1).ends_with(" -vvv"));
+    }
+
+    #[test]
+    fn switch_user_local_in_a_linked_worktree() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("main");
+        let linked = dir.path().join("linked");
+        std::fs::create_dir(&main).unwrap();
+        run_git(&main, &["init", "-q"]);
+        run_git(&main, &["commit", "-q", "--allow-empty", "-m", "first"]);
+        run_git(&main, &["worktree", "add", "-q", "../linked"]);
+        run_git(&main, &["config", "extensions.worktreeConfig", "true"]);
+
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("alice")).unwrap();
+        gus.users.add(user("bob")).unwrap();
+        let git = Path::new("git");
+        let email = |dir: &Path| get_git_config(git, dir, "worktree", "user.email").unwrap();
+
+        gus.switch_user_local("alice", &linked, "worktree").unwrap();
+        assert_eq!(email(&linked).as_deref(), Some("alice@example.com"));
+        assert_eq!(email(&main), None);
+
+        // the local scope of a linked worktree is the config shared with the main worktree
+        gus.switch_user_local("bob", &linked, "local").unwrap();
+        assert_eq!(
+            get_git_config(git, &main, "local", "user.email")
+                .unwrap()
+                .as_deref(),
+            Some("bob@example.com")
+        );
+        assert_eq!(email(&linked).as_deref(), Some("alice@example.com"));
+    }
+
+    #[test]
+    fn unset_script_clears_the_session_but_keeps_coauthors() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.isolate_git_config = true;
+        let mut signer = user("alice");
+        signer.sign_format = Some(SignFormat::Ssh);
+        let env = gus.build_env(&signer, &SwitchOptions::default()).unwrap();
+        let count = git_config_env(&env).len();
+        assert!(count > 0);
+
+        let script = format!(
+            "export GUS_COAUTHORS='bob <bob@example.com>'\n{}{}env | grep -E '^(GIT|GUS)_' | sort\n",
+            format_exports(Shell::Bash, &env),
+            build_unset_script(Shell::Bash, 0, count),
+        );
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(script)
+            .env_clear()
+            .env("PATH", env::var("PATH").unwrap())
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "GUS_COAUTHORS=bob <bob@example.com>\n"
+        );
+    }
+
+    #[test]
+    fn activate_script_is_inlined_after_the_exports() {
+        let dir = tempfile::tempdir().unwrap();
+        let gus = switcher(dir.path());
+        let activate_script = dir.path().join("activate.sh");
+        std::fs::write(&activate_script, "export AWS_PROFILE=\"$GUS_USER_ID-aws\"").unwrap();
+        let mut alice = user("alice");
+        alice.activate_script = Some(activate_script.clone());
+
+        let script = gus
+            .build_session_script(&alice, &SwitchOptions::default())
+            .unwrap();
+        assert!(script.ends_with("export AWS_PROFILE=\"$GUS_USER_ID-aws\"\n"));
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(format!("{}echo \"$AWS_PROFILE\"", script))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "alice-aws\n");
+
+        std::fs::remove_file(&activate_script).unwrap();
+        let err = gus
+            .build_session_script(&alice, &SwitchOptions::default())
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("activate script does not exist"));
+    }
+
+    #[test]
+    fn agent_backed_users_have_no_key_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.per_user_ssh_agent = true;
+        let mut agent = user("agent");
+        agent.agent_backed = true;
+        gus.add_user(agent.clone(), None, false, false).unwrap();
+        assert!(!dir.path().join("sshkeys/id_agent").exists());
+
+        let command = gus.get_ssh_command(&agent, 0).unwrap();
+        assert_eq!(command, "ssh -F /dev/null");
+        assert!(gus.get_ssh_agent_script(&agent).is_none());
+        assert!(gus.add_sshkey_to_agent("agent").is_err());
+        assert!(gus.check_regenerate_sshkey("agent", true).is_err());
+
+        // agent-backed users never share a key file, not even with each other
+        let mut other = user("other");
+        other.agent_backed = true;
+        assert!(gus.find_user_sharing_sshkey(&other).is_none());
+    }
+
+    #[test]
+    fn relative_config_paths_are_resolved_against_the_current_dir() {
+        let mut config = Config {
+            users_file_path: PathBuf::from("data/users.toml"),
+            default_sshkey_dir: PathBuf::from("./keys"),
+            gitconfig_dir: PathBuf::from("/abs/gitconfigs"),
+            ssh_agent_dir: PathBuf::from("agents"),
+            pinned_known_hosts_dir: PathBuf::from("known_hosts"),
+            ..Config::default()
+        };
+        resolve_config_paths(&mut config);
+
+        let cwd = env::current_dir().unwrap();
+        assert_eq!(config.users_file_path, cwd.join("data/users.toml"));
+        assert_eq!(config.default_sshkey_dir, cwd.join("keys"));
+        assert_eq!(config.gitconfig_dir, PathBuf::from("/abs/gitconfigs"));
+        assert_eq!(config.ssh_agent_dir, cwd.join("agents"));
+        assert_eq!(config.pinned_known_hosts_dir, cwd.join("known_hosts"));
+    }
+
+    #[test]
+    fn session_script_points_ssh_at_the_users_known_hosts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        let known_hosts = dir.path().join("hosts/work known_hosts");
+        let mut work = user("work");
+        work.known_hosts = Some(known_hosts.clone());
+        gus.users.add(work).unwrap();
+        let mut options = SwitchOptions {
+            temp_dir: Some(dir.path().join("sessions")),
+            ..Default::default()
+        };
+
+        let err = gus.switch_user("work", &options).unwrap_err();
+        assert!(err.to_string().contains("use --create-known-hosts"));
+
+        options.create_known_hosts = true;
+        gus.switch_user("work", &options).unwrap();
+        assert_eq!(std::fs::read_to_string(&known_hosts).unwrap(), "");
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(". \"$1\" && printf %s \"$GIT_SSH_COMMAND\"")
+            .arg("bash")
+            .arg(get_session_script_path(options.temp_dir.as_deref()))
+            .output()
+            .unwrap();
+        assert!(String::from_utf8(output.stdout).unwrap().contains(&format!(
+            "-o UserKnownHostsFile={}",
+            shell_single_quote(&known_hosts.to_string_lossy())
+        )));
+    }
+
+    #[test]
+    fn committer_only_keeps_the_author() {
+        let dir = tempfile::tempdir().unwrap();
+        let gus = switcher(dir.path());
+        let options = SwitchOptions {
+            as_committer_only: true,
+            ..Default::default()
+        };
+        let env = gus.build_env(&user("bot"), &options).unwrap();
+        assert!(!env.iter().any(|(key, _)| key.starts_with("GIT_AUTHOR_")));
+
+        let script = gus.build_session_script(&user("bot"), &options).unwrap();
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(format!(
+                "export GIT_AUTHOR_NAME='Jane Doe' GIT_AUTHOR_EMAIL=jane@example.com\n{}\
+                 printf '%s\\n' \"$GIT_AUTHOR_NAME\" \"$GIT_AUTHOR_EMAIL\" \"$GIT_COMMITTER_EMAIL\"",
+                script
+            ))
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "Jane Doe\njane@example.com\nbot@example.com\n"
+        );
+    }
+
+    #[test]
+    fn isolated_gitconfig_is_exported_and_readable_by_git() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.isolate_git_config = true;
+        let mut work = user("work");
+        work.name = "Jane \"JD\" Doe\\".to_string();
+        gus.users.add(work.clone()).unwrap();
+        let options = SwitchOptions {
+            temp_dir: Some(dir.path().join("sessions")),
+            ..Default::default()
+        };
+
+        gus.switch_user("work", &options).unwrap();
+        let gitconfig = dir.path().join("gitconfigs/work.gitconfig");
+        assert!(std::fs::read_to_string(&gitconfig)
+            .unwrap()
+            .starts_with("# generated by gus for user 'work'"));
+
+        let output = Command::new("bash")
+            .arg("-c")
+            .arg(
+                ". \"$1\" && printf '%s\\n' \"$GIT_CONFIG_GLOBAL\" && \
+                 git config --global user.name && git config --global user.email && \
+                 git config --global core.sshCommand",
+            )
+            .arg("bash")
+            .arg(get_session_script_path(options.temp_dir.as_deref()))
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!(
+                "{}\n{}\nwork@example.com\n{}\n",
+                gitconfig.display(),
+                work.name,
+                gus.get_ssh_command(&work, 0).unwrap()
+            )
+        );
+    }
+
+    #[test]
+    fn ssh_agent_script_starts_the_agent_and_adds_the_key_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(&dir.path().join("data $HOME"));
+        gus.config.per_user_ssh_agent = true;
+        let script = gus.get_ssh_agent_script(&user("work")).unwrap();
+
+        // ssh-add -l exits 2 without an agent, 1 for an empty agent and 0 once the key is added
+        let bin = dir.path().join("bin");
+        std::fs::create_dir(&bin).unwrap();
+        let log = dir.path().join("log");
+        for (name, body) in [
+            ("ssh-agent", "touch \"$2\"; echo \"agent $2\" >>\"$LOG\""),
+            (
+                "ssh-add",
+                "if [ \"$1\" = -l ]; then\n\
+                 [ -e \"$SSH_AUTH_SOCK\" ] || exit 2\n\
+                 [ -e \"$SSH_AUTH_SOCK.key\" ] || exit 1\n\
+                 exit 0\nfi\n\
+                 touch \"$SSH_AUTH_SOCK.key\"; echo \"add $SSH_AUTH_SOCK $1\" >>\"$LOG\"",
+            ),
+        ] {
+            let path = bin.join(name);
+            std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        for _ in 0..2 {
+            let status = Command::new("sh")
+                .arg("-c")
+                .arg(&script)
+                .env(
+                    "PATH",
+                    format!("{}:{}", bin.display(), env::var("PATH").unwrap()),
+                )
+                .env("LOG", &log)
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+        let data = dir.path().join("data $HOME");
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            format!(
+                "agent {socket}\nadd {socket} {key}\n",
+                socket = data.join("agents/work.sock").display(),
+                key = data.join("sshkeys/id_work").display(),
+            )
+        );
+    }
+
+    fn ssh_keygen(path: &Path, passphrase: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let status = Command::new("ssh-keygen")
+            .args([
+                "-q", "-t", "ed25519", "-N", passphrase, "-C", "fixture", "-f",
+            ])
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn verify_sshkey_pair_detects_a_mismatched_public_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("work")).unwrap();
+        let key = dir.path().join("sshkeys/id_work");
+        ssh_keygen(&key, "");
+        assert!(gus.verify_sshkey_pair("work").unwrap());
+
+        // a different comment is still the same key
+        let pubkey = std::fs::read_to_string(get_public_key_path(&key)).unwrap();
+        std::fs::write(
+            get_public_key_path(&key),
+            pubkey.replace("fixture", "renamed"),
+        )
+        .unwrap();
+        assert!(gus.verify_sshkey_pair("work").unwrap());
+
+        let other = dir.path().join("other/id_other");
+        ssh_keygen(&other, "");
+        std::fs::copy(get_public_key_path(&other), get_public_key_path(&key)).unwrap();
+        assert!(!gus.verify_sshkey_pair("work").unwrap());
+    }
+
+    #[test]
+    fn set_selects_users_by_email_or_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        let mut work = user("work");
+        work.name = "Jane Doe".to_string();
+        let mut home = user("home");
+        home.name = "Jane Doe".to_string();
+        gus.users.add(work).unwrap();
+        gus.users.add(home).unwrap();
+        gus.users.add(user("oss")).unwrap();
+
+        assert_eq!(gus.find_user("work@example.com").unwrap().id, "work");
+        assert_eq!(gus.find_user("oss name").unwrap().id, "oss");
+        let script = gus
+            .get_session_script("home@example.com", &SwitchOptions::default())
+            .unwrap();
+        assert!(script.contains("export GUS_USER_ID='home'\n"));
+
+        let err = gus.find_user("Jane Doe").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<UserError>(),
+            Some(UserError::Ambiguous { candidates, .. }) if candidates.len() == 2
+        ));
+        let err = gus.find_user("nobody@example.com").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<UserError>(),
+            Some(UserError::NoMatch(selector)) if selector == "nobody@example.com"
+        ));
+    }
+
+    #[test]
+    fn config_from_a_reader_lists_users_but_is_read_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let users_file = dir.path().join("users.toml");
+        let mut users = Users::new();
+        users.add(user("work")).unwrap();
+        users.add(user("home")).unwrap();
+        users.save(&users_file).unwrap();
+        let config = format!(
+            "users_file_path = {:?}\ndefault_sshkey_dir = {:?}\n",
+            users_file,
+            dir.path().join("sshkeys")
+        );
+
+        let mut gus = GitUserSwitcher::from_reader(config.as_bytes(), None).unwrap();
+        let mut ids = gus
+            .list_users()
+            .into_iter()
+            .map(|user| user.id.clone())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(ids, ["home", "work"]);
+
+        let err = gus.add_user(user("oss"), None, false, false).unwrap_err();
+        assert!(err.to_string().contains("config is read from stdin"));
+        assert!(!Users::open(&users_file).unwrap().exists("oss"));
+
+        assert!(GitUserSwitcher::from_reader("users_file_path = [".as_bytes(), None).is_err());
+    }
+
+    #[test]
+    fn read_only_data_dir_is_refused_before_any_change() {
+        // root can write anywhere, so there is nothing to refuse
+        if unsafe { libc::geteuid() } == 0 {
+            return;
+        }
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        let mut gus = switcher(&data);
+        std::fs::create_dir_all(data.join("sshkeys")).unwrap();
+        gus.users.add(user("work")).unwrap();
+        gus.users.save(&gus.config.users_file_path).unwrap();
+        let saved = std::fs::read_to_string(&gus.config.users_file_path).unwrap();
+        for path in [&data, &data.join("sshkeys")] {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o555)).unwrap();
+        }
+        std::fs::set_permissions(
+            &gus.config.users_file_path,
+            std::fs::Permissions::from_mode(0o444),
+        )
+        .unwrap();
+
+        let err = gus
+            .add_user(user("home"), Some("passphrase"), false, false)
+            .unwrap_err();
+        assert!(err.to_string().contains("permission denied"));
+        assert!(!gus.users.exists("home"));
+        assert!(gus.remove_user("work").is_err());
+        assert!(gus.users.exists("work"));
+        assert_eq!(
+            std::fs::read_to_string(&gus.config.users_file_path).unwrap(),
+            saved
+        );
+        assert!(!data.join("sshkeys/id_home").exists());
+
+        std::fs::set_permissions(&data, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::set_permissions(data.join("sshkeys"), std::fs::Permissions::from_mode(0o755))
+            .unwrap();
+    }
+
+    #[test]
+    fn ssh_command_templates_are_rendered() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.ssh_command_templates.insert(
+            "corp".to_string(),
+            "corp-ssh --key {key} {options} -J bastion".to_string(),
+        );
+        let mut work = user("work");
+        work.ssh_template = Some("corp".to_string());
+        work.known_hosts = Some(PathBuf::from("/etc/corp/known_hosts"));
+
+        assert_eq!(
+            gus.get_ssh_command(&work, 1).unwrap(),
+            format!(
+                "corp-ssh --key {} -o UserKnownHostsFile='/etc/corp/known_hosts' -v -J bastion",
+                shell_single_quote(&dir.path().join("sshkeys/id_work").to_string_lossy())
+            )
+        );
+        work.agent_backed = true;
+        work.known_hosts = None;
+        assert_eq!(
+            gus.get_ssh_command(&work, 0).unwrap(),
+            "corp-ssh --key   -J bastion"
+        );
+
+        work.ssh_template = Some("missing".to_string());
+        let err = gus.add_user(work, None, false, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ssh command template 'missing' is not defined in ssh_command_templates"
+        );
+        assert!(!gus.users.exists("work"));
+    }
+
+    #[test]
+    fn then_command_runs_with_the_users_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        let mut work = user("work");
+        work.eol = Some(crate::user::Eol::Lf);
+        gus.users.add(work).unwrap();
+        let sh = |script: &str| ["sh", "-c", script].map(str::to_string).to_vec();
+
+        let status = gus
+            .run_as_user(
+                "work",
+                &SwitchOptions::default(),
+                &sh("test \"$GUS_USER_ID\" = work && \
+                     test \"$GIT_AUTHOR_EMAIL\" = work@example.com && \
+                     test \"$GIT_COMMITTER_NAME\" = 'work name' && \
+                     test \"$(git config core.eol)\" = lf"),
+            )
+            .unwrap();
+        assert!(status.success());
+
+        let status = gus
+            .run_as_user("work", &SwitchOptions::default(), &sh("exit 7"))
+            .unwrap();
+        assert_eq!(status.code(), Some(7));
+        assert!(gus
+            .run_as_user("work", &SwitchOptions::default(), &[])
+            .is_err());
+    }
+
+    #[test]
+    fn require_signing_exports_without_a_signing_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let gus = switcher(dir.path());
+        let mut signer = user("a");
+        signer.require_signing = true;
+
+        let config = git_config_env(&gus.build_env(&signer, &SwitchOptions::default()).unwrap());
+        assert_eq!(
+            config,
+            [("commit.gpgsign", "true"), ("tag.gpgsign", "true")]
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    /// A switcher loaded from a config file whose data lives in `dir/old`.
+    fn switcher_from_file(dir: &Path) -> (GitUserSwitcher, PathBuf) {
+        let config_path = dir.join("config.toml");
+        Config {
+            users_file_path: dir.join("old/users.toml"),
+            default_sshkey_dir: dir.join("old/sshkeys"),
+            session_mode: SessionMode::Eval,
+            ..Config::default()
+        }
+        .save(&config_path)
+        .unwrap();
+        let mut gus = GitUserSwitcher::open(&config_path, None).unwrap();
+        gus.users.add(user("work")).unwrap();
+        gus.users.save(&gus.config.users_file_path).unwrap();
+        write_key_pair(&dir.join("old/sshkeys/id_work"));
+        (gus, config_path)
+    }
+
+    #[test]
+    fn relocate_data_updates_only_the_moved_paths_in_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut gus, config_path) = switcher_from_file(dir.path());
+
+        gus.relocate_data(&dir.path().join("new")).unwrap();
+        assert!(dir.path().join("new/sshkeys/id_work").exists());
+        assert!(!dir.path().join("old/users.toml").exists());
+        let saved = Config::open(&config_path).unwrap();
+        assert_eq!(saved.users_file_path, dir.path().join("new/users.toml"));
+        assert_eq!(saved.default_sshkey_dir, dir.path().join("new/sshkeys"));
+        assert_eq!(saved.session_mode, SessionMode::Eval);
+        assert!(Users::open(&saved.users_file_path).unwrap().exists("work"));
+    }
+
+    #[test]
+    fn users_file_override_is_never_written_to_the_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, config_path) = switcher_from_file(dir.path());
+        let other = dir.path().join("other.toml");
+        Users::new().save(&other).unwrap();
+        let mut gus = GitUserSwitcher::open(&config_path, Some(&other)).unwrap();
+        let original = std::fs::read_to_string(&config_path).unwrap();
+
+        let err = gus.relocate_data(&dir.path().join("new")).unwrap_err();
+        assert!(err.to_string().contains("cannot be used with --users-file"));
+        assert!(dir.path().join("old/sshkeys/id_work").exists());
+        assert_eq!(std::fs::read_to_string(&config_path).unwrap(), original);
+
+        gus.relocate_sshkeys(&dir.path().join("keys"), false)
+            .unwrap();
+        let saved = Config::open(&config_path).unwrap();
+        assert_eq!(saved.users_file_path, dir.path().join("old/users.toml"));
+        assert_eq!(saved.default_sshkey_dir, dir.path().join("keys"));
+    }
+
+    #[test]
+    fn shared_key_collisions_include_default_key_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("a")).unwrap();
+        write_key_pair(&dir.path().join("sshkeys/id_a"));
+
+        // an explicit path pointing at another user's default key
+        let mut b = user("b");
+        b.set_primary_key(dir.path().join("sshkeys/id_a"));
+        assert_eq!(gus.find_user_sharing_sshkey(&b).unwrap().id, "a");
+        let err = gus.check_shared_sshkey(&b, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "user 'a' already uses this ssh key (use --allow-shared-key to share it)"
+        );
+        gus.check_shared_sshkey(&b, true).unwrap();
+        gus.add_user(b, None, false, true).unwrap();
+
+        // the sharing is seen from both sides, but never as a user matching itself
+        let a = gus.users.get("a").unwrap().clone();
+        assert_eq!(gus.find_user_sharing_sshkey(&a).unwrap().id, "b");
+        let mut c = user("c");
+        c.set_primary_key(dir.path().join("sshkeys/id_c"));
+        assert!(gus.find_user_sharing_sshkey(&c).is_none());
+    }
+
+    #[test]
+    fn relocate_sshkeys_skips_custom_keys_and_refuses_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        let custom_path = dir.path().join("custom/id_custom");
+        let mut custom = user("custom");
+        custom.set_primary_key(custom_path.clone());
+        let mut agent = user("agent");
+        agent.agent_backed = true;
+        for user in [user("a"), user("b"), custom, agent] {
+            gus.users.add(user).unwrap();
+        }
+        write_key_pair(&dir.path().join("sshkeys/id_a"));
+        write_key_pair(&dir.path().join("sshkeys/id_b"));
+        write_key_pair(&custom_path);
+        let new_dir = dir.path().join("moved");
+
+        let planned = gus.relocate_sshkeys(&new_dir, true).unwrap();
+        let targets = planned
+            .iter()
+            .map(|(_, to)| to.strip_prefix(&new_dir).unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(targets, ["id_a", "id_a.pub", "id_b", "id_b.pub"]);
+
+        // an existing file at a target stops the move before anything is touched
+        std::fs::create_dir_all(&new_dir).unwrap();
+        std::fs::write(new_dir.join("id_b.pub"), "other").unwrap();
+        let err = gus.relocate_sshkeys(&new_dir, false).unwrap_err();
+        assert!(err.to_string().starts_with("file already exists"));
+        assert!(dir.path().join("sshkeys/id_a").exists());
+        assert_eq!(gus.config.default_sshkey_dir, dir.path().join("sshkeys"));
+
+        std::fs::remove_file(new_dir.join("id_b.pub")).unwrap();
+        gus.relocate_sshkeys(&new_dir, false).unwrap();
+        assert!(new_dir.join("id_b.pub").exists());
+        assert!(custom_path.exists());
+        assert_eq!(
+            Config::open(&dir.path().join("config.toml"))
+                .unwrap()
+                .default_sshkey_dir,
+            new_dir
+        );
+    }
+
+    #[test]
+    fn setup_git_function_prints_the_custom_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.session_mode = SessionMode::Eval;
+        gus.config.force_use_gus = true;
+        gus.config.force_use_gus_message =
+            Some("Pick an identity for $PWD; don't `guess`".to_string());
+        gus.config.git_path = Some(dir.path().join("git"));
+        write_echo_tool(gus.config.git_path.as_ref().unwrap(), "configured git");
+
+        // the app itself is replaced so that `list` and `set` only report their arguments
+        let mut child = Command::new("bash")
+            .arg("-c")
+            .arg(format!(
+                "{}function {}() {{ echo \"app $*\"; }}\ngit status",
+                gus.get_setup_script(Shell::Bash),
+                get_app_name()
+            ))
+            .env_remove("GUS_LOADED_FLAG")
+            .env_remove("GUS_USER_ID")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        std::io::Write::write_all(&mut child.stdin.take().unwrap(), b"work\n").unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            "Pick an identity for $PWD; don't `guess`\n\
+             Users:\napp list\nEnter user id: app set work\nconfigured git\nstatus\n"
+        );
+
+        let fish = gus.get_setup_script(Shell::Fish);
+        assert!(fish.contains("echo 'Pick an identity for $PWD; don\\'t `guess`'\n"));
+    }
+
+    #[test]
+    fn disallowed_key_types_are_rejected_on_add_and_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.config.default_sshkey_type = SshKeyType::Dsa;
+        gus.get_sshkey_type().unwrap();
+
+        gus.config.allowed_sshkey_types = vec![SshKeyType::Ed25519];
+        let err = gus
+            .add_user(user("work"), Some("passphrase"), false, false)
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("ssh key type 'dsa' is not allowed"));
+        assert!(!dir.path().join("sshkeys/id_work").exists());
+        assert!(!gus.config.users_file_path.exists());
+
+        let sshkey_path = dir.path().join("sshkeys/id_home");
+        write_key_pair(&sshkey_path);
+        gus.users.add(user("home")).unwrap();
+        let public_key = std::fs::read_to_string(get_public_key_path(&sshkey_path)).unwrap();
+        assert!(gus.rotate_sshkey("home", "passphrase").is_err());
+        assert_eq!(
+            std::fs::read_to_string(get_public_key_path(&sshkey_path)).unwrap(),
+            public_key
+        );
+    }
+
+    #[test]
+    fn users_file_override_skips_a_corrupt_default_users_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_, config_path) = switcher_from_file(dir.path());
+        std::fs::write(dir.path().join("old/users.toml"), "[users\n").unwrap();
+        assert!(GitUserSwitcher::open(&config_path, None).is_err());
+
+        let other = dir.path().join("other.toml");
+        let mut users = Users::new();
+        users.add(user("home")).unwrap();
+        users.save(&other).unwrap();
+        let gus = GitUserSwitcher::open(&config_path, Some(&other)).unwrap();
+        assert!(gus.users.exists("home"));
+        assert!(!gus.users.exists("work"));
+        assert_eq!(gus.config.users_file_path, other);
+
+        let config = std::fs::read_to_string(&config_path).unwrap();
+        let gus = GitUserSwitcher::from_reader(config.as_bytes(), Some(&other)).unwrap();
+        assert!(gus.users.exists("home"));
+    }
+
+    #[test]
+    fn reconcile_overwrites_a_stale_local_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut gus = switcher(dir.path());
+        gus.users.add(user("alice")).unwrap();
+        let alice = gus.users.get("alice").unwrap();
+        let repo = dir.path().join("repo");
+        std::fs::create_dir_all(&repo).unwrap();
+        run_git(&repo, &["init", "-q"]);
+        run_git(&repo, &["config", "user.email", "old@example.com"]);
+
+        let changes = gus.reconcile_git_config(alice, &repo, "local").unwrap();
+        assert_eq!(
+            changes,
+            [
+                ("user.name".to_string(), None, "alice name".to_string()),
+                (
+                    "user.email".to_string(),
+                    Some("old@example.com".to_string()),
+                    "alice@example.com".to_string()
+                ),
+            ]
+        );
+        let git = Path::new("git");
+        let get = |key| get_git_config(git, &repo, "local", key).unwrap();
+        assert_eq!(get("user.name").as_deref(), Some("alice name"));
+        assert_eq!(get("user.email").as_deref(), Some("alice@example.com"));
+
+        assert!(gus
+            .reconcile_git_config(alice, &repo, "local")
+            .unwrap()
+            .is_empty());
     }
 }
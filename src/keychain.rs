@@ -0,0 +1,77 @@
+use anyhow::{Context, Result};
+use keyring::Entry;
+
+const SERVICE_NAME: &str = "gus";
+
+fn entry(id: &str) -> Result<Entry> {
+    Entry::new(SERVICE_NAME, id)
+        .with_context(|| format!("failed to open keychain entry for user: {}", id))
+}
+
+pub fn store_passphrase(id: &str, passphrase: &str) -> Result<()> {
+    store_in(&entry(id)?, id, passphrase)
+}
+
+pub fn get_passphrase(id: &str) -> Result<String> {
+    get_from(&entry(id)?, id)
+}
+
+pub fn delete_passphrase(id: &str) -> Result<()> {
+    delete_from(&entry(id)?, id)
+}
+
+fn store_in(entry: &Entry, id: &str, passphrase: &str) -> Result<()> {
+    entry
+        .set_password(passphrase)
+        .with_context(|| format!("failed to store ssh key passphrase for user: {}", id))
+}
+
+fn get_from(entry: &Entry, id: &str) -> Result<String> {
+    entry
+        .get_password()
+        .with_context(|| format!("failed to read ssh key passphrase for user: {}", id))
+}
+
+fn delete_from(entry: &Entry, id: &str) -> Result<()> {
+    entry
+        .delete_credential()
+        .with_context(|| format!("failed to delete ssh key passphrase for user: {}", id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyring::mock::MockCredential;
+
+    /// A keychain entry kept in memory instead of the platform keychain.
+    fn mock_entry() -> Entry {
+        Entry::new_with_credential(Box::new(MockCredential::default()))
+    }
+
+    #[test]
+    fn passphrase_round_trip() {
+        let entry = mock_entry();
+        store_in(&entry, "alice", "correct horse").unwrap();
+        assert_eq!(get_from(&entry, "alice").unwrap(), "correct horse");
+
+        delete_from(&entry, "alice").unwrap();
+        let err = get_from(&entry, "alice").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to read ssh key passphrase for user: alice"
+        );
+        assert!(delete_from(&entry, "alice").is_err());
+    }
+
+    #[test]
+    fn keychain_errors_name_the_user() {
+        let entry = mock_entry();
+        let credential: &MockCredential = entry.get_credential().downcast_ref().unwrap();
+        credential.set_error(keyring::Error::NoStorageAccess("keychain is locked".into()));
+        let err = store_in(&entry, "alice", "correct horse").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "failed to store ssh key passphrase for user: alice"
+        );
+    }
+}
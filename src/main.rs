@@ -1,15 +1,25 @@
-use anyhow::Result;
+use std::process::ExitCode;
 
 mod cli;
 mod config;
+mod doctor;
+mod error;
 mod gus;
+#[cfg(feature = "keychain")]
+mod keychain;
 mod shell;
+mod sshconfig;
 mod sshkey;
+mod timing;
 mod user;
 
-use crate::cli::run;
+use crate::cli::{report_error, run};
 
-fn main() -> Result<()> {
-    run()?;
-    Ok(())
+fn main() -> ExitCode {
+    let result = run();
+    timing::report();
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => ExitCode::from(report_error(&err)),
+    }
 }
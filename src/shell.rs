@@ -1,26 +1,128 @@
-use anyhow::{Context, Result};
-use std::{collections::HashMap, env, os::unix::process::parent_id, path::PathBuf};
-
-pub fn str2envkey(s: &str) -> String {
-    // [a-zA-Z_][a-zA-Z0-9_]*
-    let mut result = String::new();
-    let mut chars = s.chars();
-    if let Some(c) = chars.next() {
-        if c.is_ascii_alphabetic() || c == '_' {
-            result.push(c);
-        } else {
-            result.push('_');
-            result.push(c);
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use std::{
+    env,
+    io::{ErrorKind, Write},
+    os::unix::process::parent_id,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::config::SessionMode;
+
+/// Set by the setup script to the binary its shell function calls.
+pub const APP_PATH_KEY: &str = "GUS_APP_PATH";
+
+/// Set by the fish shell function for each call so session scripts come out in fish syntax.
+const SHELL_KEY: &str = "GUS_SHELL";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl Shell {
+    /// Guesses the login shell from `$SHELL`, falling back to bash.
+    pub fn detect() -> Self {
+        let shell = env::var("SHELL").unwrap_or_default();
+        match Path::new(&shell).file_name().and_then(|name| name.to_str()) {
+            Some("fish") => Shell::Fish,
+            Some("zsh") => Shell::Zsh,
+            _ => Shell::Bash,
+        }
+    }
+
+    /// The shell whose function is running gus; bash and zsh share the same syntax.
+    pub fn of_caller() -> Self {
+        match env::var(SHELL_KEY).as_deref() {
+            Ok("fish") => Shell::Fish,
+            _ => Shell::Bash,
+        }
+    }
+
+    pub fn format_export(self, key: &str, value: &str) -> String {
+        match self {
+            Shell::Fish => format!("set -gx {} {}\n", key, fish_single_quote(value)),
+            _ => format!("export {}={}\n", key, shell_single_quote(value)),
+        }
+    }
+
+    pub fn format_unset(self, key: &str) -> String {
+        match self {
+            Shell::Fish => format!("set -e {}\n", key),
+            _ => format!("unset {}\n", key),
         }
     }
-    result.extend(chars.filter(|c| c.is_ascii_alphanumeric() || *c == '_'));
-    result
 }
 
-pub fn get_session_script_path() -> PathBuf {
-    env::temp_dir()
-        .join(env::current_exe().unwrap().file_name().unwrap())
-        .join(format!("session{}.sh", parent_id()))
+pub fn get_session_script_dir() -> PathBuf {
+    env::temp_dir().join(env::current_exe().unwrap().file_name().unwrap())
+}
+
+/// The session script of the calling shell, inside `dir` when given instead of the default directory.
+pub fn get_session_script_path(dir: Option<&Path>) -> PathBuf {
+    let file_name = format!("session{}.sh", parent_id());
+    match dir {
+        Some(dir) => dir.join(file_name),
+        None => get_session_script_dir().join(file_name),
+    }
+}
+
+/// The session script path as seen from the shell, where `$$` is the pid `gus` sees as its parent.
+/// Resolving it at runtime keeps a setup script written to a file valid for every shell sourcing it.
+fn get_shell_session_script_path() -> String {
+    format!(
+        "{}/session$$.sh",
+        get_session_script_dir().to_string_lossy()
+    )
+}
+
+pub struct SessionScript {
+    pub pid: u32,
+    pub path: PathBuf,
+    pub size: u64,
+    pub is_alive: bool,
+}
+
+pub fn list_session_scripts() -> Result<Vec<SessionScript>> {
+    let dir = get_session_script_dir();
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let entries = std::fs::read_dir(&dir)
+        .with_context(|| format!("failed to read session script directory: {}", dir.display()))?;
+
+    let mut scripts = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let pid = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.strip_prefix("session"))
+            .and_then(|name| name.strip_suffix(".sh"))
+            .and_then(|pid| pid.parse::<u32>().ok());
+        let Some(pid) = pid else {
+            continue;
+        };
+        let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+        scripts.push(SessionScript {
+            pid,
+            path,
+            size,
+            is_alive: is_process_alive(pid),
+        });
+    }
+    scripts.sort_by_key(|script| script.pid);
+    Ok(scripts)
+}
+
+fn is_process_alive(pid: u32) -> bool {
+    // signal 0 only checks whether the process exists; EPERM means it exists but isn't ours
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
 }
 
 pub fn get_app_path() -> PathBuf {
@@ -31,11 +133,11 @@ pub fn get_app_name() -> String {
     env::args().next().unwrap()
 }
 
-pub fn write_session_script(script: &str) -> Result<()> {
-    let path = get_session_script_path();
+pub fn write_session_script(script: &str, dir: Option<&Path>) -> Result<()> {
+    let path = get_session_script_path(dir);
 
     if !path.parent().unwrap().exists() {
-        std::fs::create_dir_all(&path.parent().unwrap()).with_context(|| {
+        std::fs::create_dir_all(path.parent().unwrap()).with_context(|| {
             format!(
                 "failed to create session script directory: {}",
                 path.display()
@@ -45,14 +147,95 @@ pub fn write_session_script(script: &str) -> Result<()> {
 
     std::fs::write(&path, script)
         .with_context(|| format!("failed to write session script: {}", path.display()))?;
+
+    // the shell wrapper only sources the default path, which may still hold an older session
+    if dir.is_some() {
+        let default_path = get_session_script_path(None);
+        if default_path.exists() {
+            std::fs::remove_file(&default_path).with_context(|| {
+                format!(
+                    "failed to remove session script: {}",
+                    default_path.display()
+                )
+            })?;
+        }
+    }
     Ok(())
 }
 
-pub fn get_setup_script(script: &str) -> String {
+/// Quotes `s` as a single shell word that is never expanded.
+pub fn shell_single_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Quotes `s` as a single fish word, where only `\` and `'` are special inside single quotes.
+pub fn fish_single_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Escapes everything in `pattern` except the glob characters `*`, `?`, `[` and `]`.
+pub fn shell_glob_escape(pattern: &str) -> String {
+    let mut escaped = String::with_capacity(pattern.len());
+    for c in pattern.chars() {
+        if !(c.is_ascii_alphanumeric() || "*?[]/._-".contains(c)) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// `script` is written in the syntax of `shell` and runs once the shell function is defined.
+pub fn get_setup_script(shell: Shell, session_mode: SessionMode, script: &str) -> String {
+    match (shell, session_mode) {
+        (Shell::Fish, SessionMode::File) => get_fish_setup_script(script),
+        (Shell::Fish, SessionMode::Eval) => get_fish_eval_setup_script(script),
+        (_, SessionMode::File) => get_posix_setup_script(script),
+        (_, SessionMode::Eval) => get_eval_setup_script(script),
+    }
+}
+
+/// Completes through `__complete`, passing the words after the command name up to the cursor.
+/// Only bash has `complete` by default; zsh needs `bashcompinit` first and is skipped otherwise.
+fn get_posix_completion_script() -> String {
+    format!(
+        "\
+        if type complete >/dev/null 2>&1; then\n\
+            _gus_complete() {{\n\
+                local IFS=$'\\n'\n\
+                COMPREPLY=($(\"{app_path}\" __complete \"${{COMP_WORDS[@]:1:COMP_CWORD}}\"))\n\
+            }}\n\
+            complete -F _gus_complete {app_name}\n\
+        fi\n\
+        ",
+        app_path = get_app_path().to_string_lossy(),
+        app_name = get_app_name(),
+    )
+}
+
+/// The current token is passed quoted so that an empty one still reaches `__complete`.
+fn get_fish_completion_script() -> String {
+    format!(
+        "\
+        function __gus_complete\n\
+            set -l words (commandline -opc)\n\
+            set -e words[1]\n\
+            set -l current (commandline -ct)\n\
+            \"{app_path}\" __complete $words \"$current\"\n\
+        end\n\
+        complete -c {app_name} -f -a '(__gus_complete)'\n\
+        ",
+        app_path = get_app_path().to_string_lossy(),
+        app_name = get_app_name(),
+    )
+}
+
+fn get_posix_setup_script(script: &str) -> String {
     format!(
         "\
         if [ -z ${{{loaded_flag_key}}} ]; then\n\
             export {loaded_flag_key}=1\n\
+            export {app_path_key}=\"{app_path}\"\n\
             rm -f \"{session_script_path}\"\n\
             function {app_name}() {{\n\
                 \"{app_path}\" \"$@\"\n\
@@ -60,14 +243,453 @@ pub fn get_setup_script(script: &str) -> String {
                 if [ $status -ne 0 ]; then\n\
                     return $status\n\
                 fi\n\
-                source \"{session_script_path}\"\n\
+                if [ -f \"{session_script_path}\" ]; then\n\
+                    source \"{session_script_path}\"\n\
+                fi\n\
+            }}\n\
+            {completion}\
+            {script}\
+        fi\n\
+        ",
+        loaded_flag_key = "GUS_LOADED_FLAG",
+        app_path_key = APP_PATH_KEY,
+        app_path = get_app_path().to_string_lossy(),
+        app_name = get_app_name(),
+        session_script_path = get_shell_session_script_path(),
+        completion = get_posix_completion_script(),
+    )
+}
+
+/// Only `set`, `unset` and `coauthor add|remove` print exports in eval mode; everything else keeps its stdout,
+/// as does `set --dump-script`, whose script is meant to be read rather than run.
+fn get_eval_setup_script(script: &str) -> String {
+    format!(
+        "\
+        if [ -z ${{{loaded_flag_key}}} ]; then\n\
+            export {loaded_flag_key}=1\n\
+            export {app_path_key}=\"{app_path}\"\n\
+            function {app_name}() {{\n\
+                local arg\n\
+                for arg in \"$@\"; do\n\
+                    if [ \"$arg\" = --dump-script ]; then\n\
+                        \"{app_path}\" \"$@\"\n\
+                        return $?\n\
+                    fi\n\
+                done\n\
+                case \"$1 $2\" in\n\
+                    \"set \"*|\"unset \"*|\"coauthor add\"|\"coauthor remove\")\n\
+                        local exports\n\
+                        exports=\"$(\"{app_path}\" \"$@\")\" || return $?\n\
+                        eval \"$exports\"\n\
+                        ;;\n\
+                    *)\n\
+                        \"{app_path}\" \"$@\"\n\
+                        ;;\n\
+                esac\n\
             }}\n\
+            {completion}\
             {script}\
         fi\n\
         ",
         loaded_flag_key = "GUS_LOADED_FLAG",
+        app_path_key = APP_PATH_KEY,
+        app_path = get_app_path().to_string_lossy(),
+        app_name = get_app_name(),
+        completion = get_posix_completion_script(),
+    )
+}
+
+/// Fish has no `$$`, so the session script path is resolved from `$fish_pid` instead.
+fn get_fish_setup_script(script: &str) -> String {
+    let session_script_path = format!(
+        "{}/session$fish_pid.sh",
+        get_session_script_dir().to_string_lossy()
+    );
+    format!(
+        "\
+        if not set -q {loaded_flag_key}\n\
+            set -gx {loaded_flag_key} 1\n\
+            set -gx {app_path_key} \"{app_path}\"\n\
+            rm -f \"{session_script_path}\"\n\
+            function {app_name}\n\
+                env {shell_key}=fish \"{app_path}\" $argv\n\
+                or return $status\n\
+                if test -f \"{session_script_path}\"\n\
+                    source \"{session_script_path}\"\n\
+                end\n\
+            end\n\
+            {completion}\
+            {script}\
+        end\n\
+        ",
+        loaded_flag_key = "GUS_LOADED_FLAG",
+        app_path_key = APP_PATH_KEY,
+        shell_key = SHELL_KEY,
         app_path = get_app_path().to_string_lossy(),
         app_name = get_app_name(),
-        session_script_path = get_session_script_path().to_string_lossy(),
+        completion = get_fish_completion_script(),
     )
 }
+
+fn get_fish_eval_setup_script(script: &str) -> String {
+    format!(
+        "\
+        if not set -q {loaded_flag_key}\n\
+            set -gx {loaded_flag_key} 1\n\
+            set -gx {app_path_key} \"{app_path}\"\n\
+            function {app_name}\n\
+                if contains -- --dump-script $argv\n\
+                    env {shell_key}=fish \"{app_path}\" $argv\n\
+                    return $status\n\
+                end\n\
+                switch \"$argv[1] $argv[2]\"\n\
+                    case 'set *' 'unset *' 'coauthor add' 'coauthor remove'\n\
+                        set -l exports (env {shell_key}=fish \"{app_path}\" $argv)\n\
+                        or return $status\n\
+                        printf '%s\\n' $exports | source\n\
+                    case '*'\n\
+                        \"{app_path}\" $argv\n\
+                end\n\
+            end\n\
+            {completion}\
+            {script}\
+        end\n\
+        ",
+        loaded_flag_key = "GUS_LOADED_FLAG",
+        app_path_key = APP_PATH_KEY,
+        shell_key = SHELL_KEY,
+        app_path = get_app_path().to_string_lossy(),
+        app_name = get_app_name(),
+        completion = get_fish_completion_script(),
+    )
+}
+
+const INSTALL_BEGIN_MARKER: &str = "# >>> gus setup >>>";
+const INSTALL_END_MARKER: &str = "# <<< gus setup <<<";
+
+/// The rc file `setup --install` writes to for `shell`.
+pub fn get_rc_file_path(shell: Shell) -> PathBuf {
+    let home = dirs::home_dir().unwrap();
+    match shell {
+        Shell::Bash => home.join(".bashrc"),
+        Shell::Zsh => env::var_os("ZDOTDIR")
+            .map(PathBuf::from)
+            .unwrap_or(home)
+            .join(".zshrc"),
+        Shell::Fish => home.join(".config/fish/config.fish"),
+    }
+}
+
+/// Appends the line loading the setup script to `rc_path`, between markers so it is only added once.
+/// Returns false when the markers are already there.
+pub fn install_setup_line(shell: Shell, rc_path: &Path) -> Result<bool> {
+    let contents = match std::fs::read_to_string(rc_path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            return Err(e).with_context(|| format!("failed to read rc file: {}", rc_path.display()))
+        }
+    };
+    if contents.contains(INSTALL_BEGIN_MARKER) {
+        return Ok(false);
+    }
+
+    let app_name = get_app_name();
+    let line = match shell {
+        Shell::Bash => format!("eval \"$({} setup --shell bash)\"", app_name),
+        Shell::Zsh => format!("eval \"$({} setup --shell zsh)\"", app_name),
+        Shell::Fish => format!("{} setup --shell fish | source", app_name),
+    };
+    let separator = if contents.is_empty() || contents.ends_with('\n') {
+        ""
+    } else {
+        "\n"
+    };
+
+    if let Some(parent) = rc_path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory: {}", parent.display()))?;
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(rc_path)
+        .with_context(|| format!("failed to open rc file: {}", rc_path.display()))?;
+    write!(
+        file,
+        "{}{}\n{}\n{}\n",
+        separator, INSTALL_BEGIN_MARKER, line, INSTALL_END_MARKER
+    )
+    .with_context(|| format!("failed to write rc file: {}", rc_path.display()))?;
+    Ok(true)
+}
+
+/// Runs the script through the shell's parse-only mode, which executes nothing.
+pub fn validate_setup_script(shell: Shell, script: &str) -> Result<()> {
+    let (program, arg) = match shell {
+        Shell::Bash => ("bash", "-n"),
+        Shell::Zsh => ("zsh", "-n"),
+        Shell::Fish => ("fish", "--no-execute"),
+    };
+    let mut child = match Command::new(program)
+        .arg(arg)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            bail!(
+                "{} is not installed; cannot validate the setup script",
+                program
+            )
+        }
+        Err(e) => return Err(e).with_context(|| format!("failed to run {}", program)),
+    };
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(script.as_bytes())
+        .with_context(|| format!("failed to pass the setup script to {}", program))?;
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("failed to wait for {}", program))?;
+
+    if !output.status.success() {
+        bail!(
+            "setup script has syntax errors:\n{}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// Values that break or inject into a script when interpolated without quoting.
+    const UNSAFE_VALUES: [&str; 6] = [
+        "Bad\"; rm -rf ~ #",
+        "$HOME ${PATH} $(touch pwned)",
+        "`touch pwned`",
+        "first line\nsecond line",
+        "it's",
+        "back\\slash '\\'' end",
+    ];
+
+    /// Runs `script` in a scratch directory and returns its stdout, or None when `program` is not installed.
+    fn run_shell(program: &str, script: &str) -> Option<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let output = match Command::new(program)
+            .arg("-c")
+            .arg(script)
+            .current_dir(dir.path())
+            .env("HOME", dir.path())
+            .output()
+        {
+            Ok(output) => output,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => panic!("failed to run {}: {}", program, e),
+        };
+        assert!(
+            output.status.success(),
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(
+            !dir.path().join("pwned").exists(),
+            "{} ran a command",
+            program
+        );
+        Some(String::from_utf8(output.stdout).unwrap())
+    }
+
+    #[test]
+    fn exports_are_inert_in_posix_shells() {
+        for shell in [Shell::Bash, Shell::Zsh] {
+            let program = format!("{:?}", shell).to_lowercase();
+            for value in UNSAFE_VALUES {
+                let script = format!(
+                    "{}printf '%s' \"$GUS_TEST\"",
+                    shell.format_export("GUS_TEST", value)
+                );
+                if let Some(output) = run_shell(&program, &script) {
+                    assert_eq!(output, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn exports_are_inert_in_fish() {
+        for value in UNSAFE_VALUES {
+            let script = format!(
+                "{}printf '%s' \"$GUS_TEST\"",
+                Shell::Fish.format_export("GUS_TEST", value)
+            );
+            if let Some(output) = run_shell("fish", &script) {
+                assert_eq!(output, value);
+            }
+        }
+    }
+
+    #[test]
+    fn single_quote_escapes_embedded_quotes() {
+        assert_eq!(shell_single_quote("it's"), "'it'\\''s'");
+        assert_eq!(
+            Shell::Bash.format_export("GIT_AUTHOR_NAME", "Bad\"; rm -rf ~ #"),
+            "export GIT_AUTHOR_NAME='Bad\"; rm -rf ~ #'\n"
+        );
+        assert_eq!(fish_single_quote("it's \\n"), "'it\\'s \\\\n'");
+        assert_eq!(
+            Shell::Fish.format_export("GIT_AUTHOR_NAME", "$HOME `id`"),
+            "set -gx GIT_AUTHOR_NAME '$HOME `id`'\n"
+        );
+    }
+
+    #[test]
+    fn eval_wrappers_do_not_eval_dumped_scripts() {
+        let bash = get_setup_script(Shell::Bash, SessionMode::Eval, "");
+        assert!(bash.contains("if [ \"$arg\" = --dump-script ]; then\n"));
+        assert!(validate_setup_script(Shell::Bash, &bash).is_ok());
+
+        let fish = get_setup_script(Shell::Fish, SessionMode::Eval, "");
+        assert!(fish.contains("if contains -- --dump-script $argv\n"));
+    }
+
+    #[test]
+    fn bash_setup_registers_completion() {
+        for mode in [SessionMode::File, SessionMode::Eval] {
+            let script = format!(
+                "{}complete -p {}\n",
+                get_setup_script(Shell::Bash, mode, ""),
+                get_app_name()
+            );
+            let Some(output) = run_shell("bash", &script) else {
+                return;
+            };
+            assert!(
+                output.starts_with("complete -F _gus_complete "),
+                "{}",
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn install_setup_line_is_idempotent() {
+        let dir = tempfile::tempdir().unwrap();
+        let rc_path = dir.path().join(".bashrc");
+        std::fs::write(&rc_path, "export EDITOR=vi").unwrap();
+
+        assert!(install_setup_line(Shell::Bash, &rc_path).unwrap());
+        let installed = std::fs::read_to_string(&rc_path).unwrap();
+        assert!(!install_setup_line(Shell::Bash, &rc_path).unwrap());
+        assert_eq!(std::fs::read_to_string(&rc_path).unwrap(), installed);
+
+        let lines: Vec<&str> = installed.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "export EDITOR=vi");
+        assert_eq!(lines[1], INSTALL_BEGIN_MARKER);
+        assert_eq!(
+            lines[2],
+            format!("eval \"$({} setup --shell bash)\"", get_app_name())
+        );
+        assert_eq!(lines[3], INSTALL_END_MARKER);
+    }
+
+    #[test]
+    fn install_setup_line_creates_missing_rc_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let rc_path = dir.path().join(".config/fish/config.fish");
+
+        assert!(install_setup_line(Shell::Fish, &rc_path).unwrap());
+        let installed = std::fs::read_to_string(&rc_path).unwrap();
+        assert!(installed.starts_with(INSTALL_BEGIN_MARKER));
+        assert!(installed.contains("setup --shell fish | source"));
+        assert!(!install_setup_line(Shell::Fish, &rc_path).unwrap());
+    }
+
+    #[test]
+    fn eval_setup_script_snapshot() {
+        let expected = r#"if [ -z ${GUS_LOADED_FLAG} ]; then
+export GUS_LOADED_FLAG=1
+export GUS_APP_PATH="@path@"
+function @name@() {
+local arg
+for arg in "$@"; do
+if [ "$arg" = --dump-script ]; then
+"@path@" "$@"
+return $?
+fi
+done
+case "$1 $2" in
+"set "*|"unset "*|"coauthor add"|"coauthor remove")
+local exports
+exports="$("@path@" "$@")" || return $?
+eval "$exports"
+;;
+*)
+"@path@" "$@"
+;;
+esac
+}
+@completion@# extra
+fi
+"#;
+        let script = get_setup_script(Shell::Bash, SessionMode::Eval, "# extra\n");
+        assert_eq!(
+            script,
+            expected
+                .replace("@completion@", &get_posix_completion_script())
+                .replace("@path@", &get_app_path().to_string_lossy())
+                .replace("@name@", &get_app_name())
+        );
+    }
+
+    #[test]
+    fn eval_setup_evals_the_exports_of_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let fake = dir.path().join("fake-gus");
+        std::fs::write(
+            &fake,
+            "#!/bin/sh\n\
+             case \"$1\" in\n\
+             set)\n\
+                 for arg; do [ \"$arg\" = --dump-script ] && echo 'export DUMPED=1' && exit 0; done\n\
+                 [ \"$2\" = bad ] && echo 'export BAD=1' && exit 3\n\
+                 echo \"export GIT_AUTHOR_NAME='$2 name'\" ;;\n\
+             *) echo \"ran $*\" ;;\n\
+             esac\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        // the wrapper keeps its name but runs the fake in place of this binary
+        let setup = get_setup_script(Shell::Bash, SessionMode::Eval, "").replace(
+            &format!("\"{}\"", get_app_path().to_string_lossy()),
+            &format!("\"{}\"", fake.display()),
+        );
+        let name = shell_single_quote(&get_app_name());
+        let script = format!(
+            "{setup}\
+             {name} set work; echo \"author=$GIT_AUTHOR_NAME\"\n\
+             {name} list\n\
+             {name} set work --dump-script; echo \"dumped=${{DUMPED-unset}}\"\n\
+             {name} set bad; echo \"status=$? bad=${{BAD-unset}}\"\n"
+        );
+        let Some(output) = run_shell("bash", &script) else {
+            return;
+        };
+        assert_eq!(
+            output,
+            "author=work name\nran list\nexport DUMPED=1\ndumped=unset\nstatus=3 bad=unset\n"
+        );
+    }
+}
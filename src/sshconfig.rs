@@ -0,0 +1,118 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+use crate::config::expand_tilde;
+
+/// A `Host` block of an ssh config file
+pub struct SshConfigHost {
+    pub alias: String,
+    pub hostname: Option<String>,
+    pub identity_file: Option<PathBuf>,
+}
+
+pub fn read_ssh_config(path: &Path) -> Result<Vec<SshConfigHost>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read ssh config: {}", path.display()))?;
+    Ok(parse_ssh_config(&contents))
+}
+
+/// Collects `Host` blocks with a concrete alias; wildcard patterns and `Match` blocks are skipped.
+/// Like ssh, only the first `HostName` and `IdentityFile` of a block are used.
+pub fn parse_ssh_config(contents: &str) -> Vec<SshConfigHost> {
+    let mut hosts: Vec<SshConfigHost> = Vec::new();
+    let mut in_host = false;
+
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (keyword, value) = match line.split_once(|c: char| c.is_whitespace() || c == '=') {
+            Some((keyword, value)) => (keyword, value.trim_start_matches([' ', '\t', '=']).trim()),
+            None => (line, ""),
+        };
+        let value = value.trim_matches('"');
+
+        match keyword.to_ascii_lowercase().as_str() {
+            "host" => {
+                let alias = value
+                    .split_whitespace()
+                    .find(|pattern| !pattern.contains(['*', '?', '!']));
+                in_host = alias.is_some();
+                if let Some(alias) = alias {
+                    hosts.push(SshConfigHost {
+                        alias: alias.to_string(),
+                        hostname: None,
+                        identity_file: None,
+                    });
+                }
+            }
+            "match" => in_host = false,
+            "hostname" if in_host => {
+                let host = hosts.last_mut().unwrap();
+                host.hostname.get_or_insert_with(|| value.to_string());
+            }
+            "identityfile" if in_host => {
+                let host = hosts.last_mut().unwrap();
+                host.identity_file
+                    .get_or_insert_with(|| expand_tilde(value));
+            }
+            _ => {}
+        }
+    }
+
+    hosts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_sample_ssh_config() {
+        let hosts = parse_ssh_config(
+            "# personal\n\
+             Host github-personal gh\n\
+             \tHostName github.com\n\
+             \tIdentityFile ~/.ssh/id_personal\n\
+             \tIdentityFile ~/.ssh/id_unused\n\
+             \n\
+             Host *.corp !bastion work\n\
+             HostName=git.corp.example\n\
+             IdentityFile \"/keys/id_work\"\n\
+             \n\
+             Host *\n\
+             IdentityFile ~/.ssh/id_default\n\
+             \n\
+             Match host other\n\
+             IdentityFile ~/.ssh/id_match\n",
+        );
+
+        let summary: Vec<_> = hosts
+            .iter()
+            .map(|host| {
+                (
+                    host.alias.as_str(),
+                    host.hostname.as_deref(),
+                    host.identity_file.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "github-personal",
+                    Some("github.com"),
+                    Some(dirs::home_dir().unwrap().join(".ssh/id_personal"))
+                ),
+                (
+                    "work",
+                    Some("git.corp.example"),
+                    Some(PathBuf::from("/keys/id_work"))
+                ),
+            ]
+        );
+    }
+}
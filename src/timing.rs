@@ -0,0 +1,79 @@
+use once_cell::sync::Lazy;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static START: Lazy<Instant> = Lazy::new(Instant::now);
+static PHASES: Lazy<Mutex<Vec<(&'static str, Duration)>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+pub fn enable() {
+    Lazy::force(&START);
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Runs `f`, recording how long it took under `label` when timing is enabled.
+pub fn time<T>(label: &'static str, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    PHASES.lock().unwrap().push((label, start.elapsed()));
+    result
+}
+
+/// Prints the recorded phases and the total run time to stderr.
+pub fn report() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    eprint!(
+        "{}",
+        format_report(&PHASES.lock().unwrap(), START.elapsed())
+    );
+}
+
+fn format_report(phases: &[(&'static str, Duration)], total: Duration) -> String {
+    phases
+        .iter()
+        .copied()
+        .chain([("total", total)])
+        .map(|(label, duration)| format!("timing: {:<20} {:?}\n", label, duration))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_lists_phases_in_order_then_the_total() {
+        let phases = [
+            ("config load", Duration::from_millis(2)),
+            ("ssh-keygen", Duration::from_micros(1500)),
+        ];
+        assert_eq!(
+            format_report(&phases, Duration::from_millis(5)),
+            "timing: config load          2ms\n\
+             timing: ssh-keygen           1.5ms\n\
+             timing: total                5ms\n"
+        );
+    }
+
+    #[test]
+    fn time_records_the_label_once_enabled() {
+        assert_eq!(time("before enable", || 1), 1);
+        enable();
+        assert_eq!(time("after enable", || 2), 2);
+
+        let phases = PHASES.lock().unwrap();
+        let labels = phases.iter().map(|(label, _)| *label).collect::<Vec<_>>();
+        assert!(!labels.contains(&"before enable"));
+        assert!(labels.contains(&"after enable"));
+    }
+}
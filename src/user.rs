@@ -1,9 +1,81 @@
 use anyhow::{ensure, Context, Result};
-use clap::Args;
+use clap::{Args, ValueEnum};
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, fmt::Display, path::PathBuf};
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 
-#[derive(Serialize, Deserialize, Debug, Clone, Args)]
+use crate::config::expand_tilde;
+use crate::error::UserError;
+use crate::sshkey::get_public_key_path;
+
+pub const DEFAULT_HOST: &str = "github.com";
+
+/// Accepted values of git's `core.autocrlf`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, ValueEnum)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum AutoCrlf {
+    True,
+    False,
+    Input,
+}
+
+impl Display for AutoCrlf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::True => "true",
+            Self::False => "false",
+            Self::Input => "input",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Accepted values of git's `core.eol`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, ValueEnum)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Eol {
+    Lf,
+    Crlf,
+    Native,
+}
+
+impl Display for Eol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Lf => "lf",
+            Self::Crlf => "crlf",
+            Self::Native => "native",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Accepted values of git's `gpg.format` that gus can set up
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum SignFormat {
+    Openpgp,
+    Ssh,
+}
+
+impl Display for SignFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Self::Openpgp => "openpgp",
+            Self::Ssh => "ssh",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, Args)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct User {
     /// The user's ID (must be unique)
     pub id: String,
@@ -15,28 +87,207 @@ pub struct User {
     /// The path to the user's ssh key
     #[clap(long, short)]
     pub sshkey_path: Option<PathBuf>,
+
+    /// The ssh key is held by an ssh-agent (e.g. Secure Enclave) and has no key file
+    #[clap(long, conflicts_with = "sshkey_path")]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub agent_backed: bool,
+
+    /// Store the ssh key passphrase in the system keychain
+    #[clap(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub passphrase_keychain: bool,
+
+    /// The git host the user belongs to (defaults to github.com)
+    #[clap(long)]
+    pub host: Option<String>,
+
+    /// The path to a known_hosts file used instead of the default one
+    #[clap(long)]
+    pub known_hosts: Option<PathBuf>,
+
+    /// The only host key (`<type> <base64>`) the git host may present; takes precedence over known_hosts
+    #[clap(long)]
+    pub host_key_pin: Option<String>,
+
+    /// The path to a script sourced after switching to the user
+    #[clap(long)]
+    pub activate_script: Option<PathBuf>,
+
+    /// The value of core.autocrlf while the user is active
+    #[clap(long)]
+    pub autocrlf: Option<AutoCrlf>,
+
+    /// The value of core.eol while the user is active
+    #[clap(long)]
+    pub eol: Option<Eol>,
+
+    /// Require commits and tags to be signed while the user is active
+    #[clap(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub require_signing: bool,
+
+    /// The key used to sign commits and tags (git's user.signingkey); setting it enables commit signing
+    #[clap(long)]
+    pub signing_key: Option<String>,
+
+    /// The signature format (git's gpg.format); ssh defaults the signing key to the user's public key
+    #[clap(long)]
+    pub sign_format: Option<SignFormat>,
+
+    /// Ask for confirmation before `gus set` switches to the user
+    #[clap(long)]
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub require_confirm: bool,
+
+    /// The protocol `gus clone` rewrites clone URLs to
+    #[clap(long)]
+    pub preferred_protocol: Option<Protocol>,
+
+    /// How many `-v` flags to pass to ssh in GIT_SSH_COMMAND
+    #[clap(long)]
+    pub ssh_verbose: Option<u8>,
+
+    /// The name of an ssh command template in the config used for GIT_SSH_COMMAND
+    #[clap(long)]
+    pub ssh_template: Option<String>,
+
+    /// The user's ssh keys; the first one takes precedence over `sshkey_path`
+    #[clap(skip)]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<KeySpec>,
+}
+
+/// The protocol used to talk to the git host
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Ssh,
+    Https,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct KeySpec {
+    /// The path to the ssh key
+    pub path: PathBuf,
+    /// The git host the key is used for
+    pub host: Option<String>,
 }
 
 impl Display for User {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}: {} <{}>", self.id, self.name, self.email)
+        write!(f, "{}: {} <{}>", self.id, self.name, self.email)?;
+        if self.agent_backed {
+            write!(f, " (agent-backed)")?;
+        }
+        Ok(())
     }
 }
 
 impl User {
+    pub fn get_host(&self) -> &str {
+        self.host.as_deref().unwrap_or(DEFAULT_HOST)
+    }
+
+    pub fn get_sshkey_comment(&self) -> String {
+        if let Some(host) = &self.host {
+            format!("{}@{}", self.get_sshkey_name(), host)
+        } else {
+            self.get_sshkey_name()
+        }
+    }
+
+    /// The configured signing key, or the user's public key file when signing with ssh.
+    pub fn get_signing_key(&self, default_sshkey_dir: &Path) -> Option<String> {
+        if let Some(signing_key) = &self.signing_key {
+            return Some(signing_key.clone());
+        }
+        if self.sign_format == Some(SignFormat::Ssh) && !self.agent_backed {
+            let sshkey_path = self.get_sshkey_path(default_sshkey_dir);
+            return Some(
+                get_public_key_path(&sshkey_path)
+                    .to_string_lossy()
+                    .to_string(),
+            );
+        }
+        None
+    }
+
+    /// Git config entries for this user. Having a signing key only turns on commit signing
+    /// when `sign_commits` is set; `require_signing` always does.
+    pub fn get_git_config(
+        &self,
+        default_sshkey_dir: &Path,
+        sign_commits: bool,
+    ) -> Vec<(String, String)> {
+        let mut config = Vec::new();
+        if let Some(autocrlf) = self.autocrlf {
+            config.push(("core.autocrlf".to_string(), autocrlf.to_string()));
+        }
+        if let Some(eol) = self.eol {
+            config.push(("core.eol".to_string(), eol.to_string()));
+        }
+        let signing_key = self.get_signing_key(default_sshkey_dir);
+        if self.require_signing || (sign_commits && signing_key.is_some()) {
+            config.push(("commit.gpgsign".to_string(), "true".to_string()));
+        }
+        if self.require_signing {
+            config.push(("tag.gpgsign".to_string(), "true".to_string()));
+        }
+        if let Some(signing_key) = signing_key {
+            config.push(("user.signingkey".to_string(), signing_key));
+        }
+        if let Some(sign_format) = self.sign_format {
+            config.push(("gpg.format".to_string(), sign_format.to_string()));
+        }
+        config
+    }
+
+    /// The explicitly configured ssh key, if any
+    pub fn primary_key(&self) -> Option<&Path> {
+        self.keys
+            .first()
+            .map(|key| key.path.as_path())
+            .or(self.sshkey_path.as_deref())
+    }
+
+    pub fn set_primary_key(&mut self, path: PathBuf) {
+        match self.keys.first_mut() {
+            Some(key) => key.path = path,
+            None => self.sshkey_path = Some(path),
+        }
+    }
+
+    /// Rewrites ssh key paths inside `from` to the same place inside `to`.
+    pub fn relocate_sshkey_paths(&mut self, from: &Path, to: &Path) {
+        let relocate = |path: &mut PathBuf| {
+            if let Ok(rest) = path.strip_prefix(from) {
+                *path = to.join(rest);
+            }
+        };
+        if let Some(path) = &mut self.sshkey_path {
+            relocate(path);
+        }
+        for key in &mut self.keys {
+            relocate(&mut key.path);
+        }
+    }
+
     pub fn get_sshkey_name(&self) -> String {
-        if let Some(path) = &self.sshkey_path {
+        if let Some(path) = self.primary_key() {
             path.file_name().unwrap().to_str().unwrap().to_string()
         } else {
             format!("id_{}", self.id)
         }
     }
 
-    pub fn get_sshkey_path(&self, default_sshkey_dir: &PathBuf) -> PathBuf {
-        if let Some(path) = &self.sshkey_path {
-            path.clone()
+    pub fn get_sshkey_path(&self, default_sshkey_dir: &Path) -> PathBuf {
+        if let Some(path) = self.primary_key() {
+            expand_tilde(path)
         } else {
-            default_sshkey_dir.join(&self.get_sshkey_name())
+            default_sshkey_dir.join(self.get_sshkey_name())
         }
     }
 }
@@ -54,29 +305,62 @@ impl Users {
         }
     }
 
-    pub fn open(path: &PathBuf) -> Result<Self> {
-        if !path.exists() {
-            let users = Self::new();
-            users.save(path)?;
-            return Ok(users);
+    pub fn open(path: &Path) -> Result<Self> {
+        let result = if path.exists() {
+            Self::read(path)
+        } else {
+            Ok(Self::new())
+        };
+
+        // a save interrupted by a crash leaves the new contents in the tmp file and the previous ones in the backup
+        if result.is_err() || !path.exists() {
+            for candidate in [with_suffix(path, ".tmp"), with_suffix(path, ".bak")] {
+                if !candidate.exists() {
+                    continue;
+                }
+                if let Ok(users) = Self::read(&candidate) {
+                    eprintln!(
+                        "warning: recovered users from {} (users file is {})",
+                        candidate.display(),
+                        if path.exists() {
+                            "unreadable"
+                        } else {
+                            "missing"
+                        }
+                    );
+                    return Ok(users);
+                }
+            }
         }
+        result
+    }
 
-        let contents = std::fs::read_to_string(&path)
+    fn read(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
             .with_context(|| format!("failed to read users file: {}", path.display()))?;
         let users = toml::from_str(&contents)
             .with_context(|| format!("failed to parse users file: {}", path.display()))?;
         Ok(users)
     }
 
+    /// Writes to a tmp file first and keeps the previous file as a backup, so a crash never loses both.
     pub fn save(&self, path: &PathBuf) -> Result<()> {
         if !path.exists() {
-            std::fs::create_dir_all(&path.parent().unwrap())
+            std::fs::create_dir_all(path.parent().unwrap())
                 .with_context(|| format!("failed to create users directory: {}", path.display()))?;
         }
 
         let contents = toml::to_string(&self)
             .with_context(|| format!("failed to serialize users file: {}", path.display()))?;
-        std::fs::write(&path, contents)
+        let tmp_path = with_suffix(path, ".tmp");
+        std::fs::write(&tmp_path, contents)
+            .with_context(|| format!("failed to write users file: {}", tmp_path.display()))?;
+        if path.exists() {
+            let bak_path = with_suffix(path, ".bak");
+            std::fs::copy(path, &bak_path)
+                .with_context(|| format!("failed to back up users file: {}", bak_path.display()))?;
+        }
+        std::fs::rename(&tmp_path, path)
             .with_context(|| format!("failed to write users file: {}", path.display()))?;
         Ok(())
     }
@@ -85,12 +369,10 @@ impl Users {
         self.hashmap.contains_key(id)
     }
 
-    pub fn add(&mut self, user: User) -> Result<()> {
-        ensure!(
-            !self.exists(&user.id),
-            "user with id '{}' already exists",
-            user.id
-        );
+    pub fn add(&mut self, user: User) -> Result<(), UserError> {
+        if self.exists(&user.id) {
+            return Err(UserError::AlreadyExists(user.id));
+        }
         self.hashmap.insert(user.id.clone(), user);
         Ok(())
     }
@@ -110,4 +392,337 @@ impl Users {
     pub fn list(&self) -> Vec<&User> {
         self.hashmap.values().collect()
     }
+
+    pub fn list_mut(&mut self) -> Vec<&mut User> {
+        self.hashmap.values_mut().collect()
+    }
+
+    pub fn find_by_email(&self, email: &str) -> Vec<&User> {
+        self.hashmap
+            .values()
+            .filter(|user| user.email == email)
+            .collect()
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Vec<&User> {
+        self.hashmap
+            .values()
+            .filter(|user| user.name == name)
+            .collect()
+    }
+
+    /// Finds a user by ID, or else by an email or name that only one user has.
+    pub fn find(&self, selector: &str) -> Result<&User, UserError> {
+        if let Some(user) = self.get(selector) {
+            return Ok(user);
+        }
+
+        for mut candidates in [self.find_by_email(selector), self.find_by_name(selector)] {
+            match candidates.len() {
+                0 => continue,
+                1 => return Ok(candidates[0]),
+                _ => {
+                    candidates.sort_by(|a, b| a.id.cmp(&b.id));
+                    return Err(UserError::Ambiguous {
+                        selector: selector.to_string(),
+                        candidates: candidates.iter().map(|user| user.to_string()).collect(),
+                    });
+                }
+            }
+        }
+
+        Err(UserError::NoMatch(selector.to_string()))
+    }
+
+    /// Users whose table key differs from their `id` field, as (key, id) pairs sorted by key.
+    pub fn find_id_mismatches(&self) -> Vec<(String, String)> {
+        let mut mismatches = self
+            .hashmap
+            .iter()
+            .filter(|(key, user)| **key != user.id)
+            .map(|(key, user)| (key.clone(), user.id.clone()))
+            .collect::<Vec<_>>();
+        mismatches.sort();
+        mismatches
+    }
+
+    /// Makes every table key and `id` field agree, keeping the key or, with `use_inner_id`, the id.
+    /// Nothing is changed if that would give two users the same id.
+    pub fn repair_ids(&mut self, use_inner_id: bool) -> Result<Vec<(String, String)>> {
+        let mismatches = self.find_id_mismatches();
+        if !use_inner_id {
+            for (key, _) in &mismatches {
+                let user = self.hashmap.get_mut(key).unwrap();
+                user.id = key.clone();
+            }
+            return Ok(mismatches);
+        }
+
+        let mut hashmap = HashMap::with_capacity(self.hashmap.len());
+        for (key, user) in &self.hashmap {
+            ensure!(
+                !hashmap.contains_key(&user.id),
+                "cannot use the inner id of '{}': another user already has the id '{}'",
+                key,
+                user.id
+            );
+            hashmap.insert(user.id.clone(), user.clone());
+        }
+        self.hashmap = hashmap;
+        Ok(mismatches)
+    }
+}
+
+/// Appends `suffix` to the file name of `path`, keeping any extension it already has.
+pub fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users(entries: &[(&str, &str, &str)]) -> Users {
+        let mut users = Users::new();
+        for (id, name, email) in entries {
+            users
+                .add(User {
+                    id: id.to_string(),
+                    name: name.to_string(),
+                    email: email.to_string(),
+                    ..Default::default()
+                })
+                .unwrap();
+        }
+        users
+    }
+
+    #[test]
+    fn find_by_id_email_or_unique_name() {
+        let users = users(&[
+            ("work", "Jane Doe", "jane@corp.example"),
+            ("home", "Jane Doe", "jane@home.example"),
+            ("oss", "J. Doe", "jane@home.example"),
+        ]);
+
+        assert_eq!(users.find("work").unwrap().id, "work");
+        assert_eq!(users.find("jane@corp.example").unwrap().id, "work");
+        assert_eq!(users.find("J. Doe").unwrap().id, "oss");
+        assert!(matches!(
+            users.find("nobody"),
+            Err(UserError::NoMatch(selector)) if selector == "nobody"
+        ));
+
+        let err = users.find("jane@home.example").unwrap_err();
+        let UserError::Ambiguous { candidates, .. } = &err else {
+            panic!("expected an ambiguous match, got {:?}", err);
+        };
+        assert_eq!(candidates.len(), 2);
+        assert!(candidates[0].starts_with("home") && candidates[1].starts_with("oss"));
+        assert!(err
+            .to_string()
+            .starts_with("'jane@home.example' matches multiple users:\n  home"));
+        assert!(matches!(
+            users.find("Jane Doe"),
+            Err(UserError::Ambiguous { .. })
+        ));
+    }
+
+    #[test]
+    fn add_rejects_an_existing_id() {
+        let mut users = users(&[("work", "Jane Doe", "jane@corp.example")]);
+        let err = users
+            .add(User {
+                id: "work".to_string(),
+                ..Default::default()
+            })
+            .unwrap_err();
+        assert!(matches!(err, UserError::AlreadyExists(id) if id == "work"));
+        assert_eq!(users.get("work").unwrap().name, "Jane Doe");
+    }
+
+    #[test]
+    fn save_keeps_a_backup_and_open_recovers_from_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.toml");
+        users(&[("work", "Jane Doe", "jane@corp.example")])
+            .save(&path)
+            .unwrap();
+        users(&[("home", "Jane Doe", "jane@home.example")])
+            .save(&path)
+            .unwrap();
+        assert!(!with_suffix(&path, ".tmp").exists());
+        assert!(Users::open(&with_suffix(&path, ".bak"))
+            .unwrap()
+            .exists("work"));
+
+        // a crash while writing leaves a torn users file behind
+        std::fs::write(&path, "[home\nid =").unwrap();
+        assert!(Users::open(&path).unwrap().exists("work"));
+    }
+
+    #[test]
+    fn open_prefers_the_tmp_file_of_an_interrupted_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.toml");
+        users(&[("old", "Jane Doe", "jane@corp.example")])
+            .save(&with_suffix(&path, ".bak"))
+            .unwrap();
+        users(&[("new", "Jane Doe", "jane@corp.example")])
+            .save(&with_suffix(&path, ".tmp"))
+            .unwrap();
+
+        let recovered = Users::open(&path).unwrap();
+        assert!(recovered.exists("new") && !recovered.exists("old"));
+
+        std::fs::write(&path, "not toml [").unwrap();
+        std::fs::remove_file(with_suffix(&path, ".tmp")).unwrap();
+        std::fs::remove_file(with_suffix(&path, ".bak")).unwrap();
+        assert!(Users::open(&path).is_err());
+    }
+
+    const MISMATCHED_USERS: &str = r#"
+[work]
+id = "job"
+name = "Jane Doe"
+email = "jane@corp.example"
+
+[home]
+id = "home"
+name = "Jane Doe"
+email = "jane@home.example"
+"#;
+
+    #[test]
+    fn repair_ids_keeps_the_table_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.toml");
+        std::fs::write(&path, MISMATCHED_USERS).unwrap();
+
+        let mut users = Users::open(&path).unwrap();
+        let mismatches = vec![("work".to_string(), "job".to_string())];
+        assert_eq!(users.find_id_mismatches(), mismatches);
+
+        assert_eq!(users.repair_ids(false).unwrap(), mismatches);
+        users.save(&path).unwrap();
+        let users = Users::open(&path).unwrap();
+        assert!(users.find_id_mismatches().is_empty());
+        assert_eq!(users.get("work").unwrap().id, "work");
+        assert!(!users.exists("job"));
+    }
+
+    #[test]
+    fn repair_ids_can_keep_the_inner_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.toml");
+        std::fs::write(&path, MISMATCHED_USERS).unwrap();
+
+        let mut users = Users::open(&path).unwrap();
+        users.repair_ids(true).unwrap();
+        users.save(&path).unwrap();
+        let users = Users::open(&path).unwrap();
+        assert!(users.find_id_mismatches().is_empty());
+        assert_eq!(users.get("job").unwrap().email, "jane@corp.example");
+        assert!(!users.exists("work"));
+
+        // using the inner id must not merge two users into one
+        std::fs::write(
+            &path,
+            format!(
+                "{}\n[other]\nid = \"job\"\nname = \"J\"\nemail = \"j@x\"\n",
+                MISMATCHED_USERS
+            ),
+        )
+        .unwrap();
+        let mut users = Users::open(&path).unwrap();
+        assert!(users.repair_ids(true).is_err());
+        assert_eq!(users.find_id_mismatches().len(), 2);
+    }
+
+    #[test]
+    fn key_specs_round_trip_through_the_users_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("users.toml");
+        std::fs::write(
+            &path,
+            r#"
+[work]
+id = "work"
+name = "Jane Doe"
+email = "jane@corp.example"
+sshkey_path = "/keys/legacy"
+
+[[work.keys]]
+path = "/keys/id_github"
+host = "github.com"
+
+[[work.keys]]
+path = "/keys/id_gitlab"
+host = "gitlab.com"
+
+[home]
+id = "home"
+name = "Jane Doe"
+email = "jane@home.example"
+"#,
+        )
+        .unwrap();
+
+        let users = Users::open(&path).unwrap();
+        let work = users.get("work").unwrap();
+        assert_eq!(work.primary_key(), Some(Path::new("/keys/id_github")));
+        assert_eq!(work.keys[1].host.as_deref(), Some("gitlab.com"));
+        users.save(&path).unwrap();
+
+        let saved = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(saved.matches("[[work.keys]]").count(), 2);
+        assert!(!saved.contains("home.keys"));
+        let users = Users::open(&path).unwrap();
+        let work = users.get("work").unwrap();
+        let keys = work
+            .keys
+            .iter()
+            .map(|key| (key.path.to_str().unwrap(), key.host.as_deref()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            [
+                ("/keys/id_github", Some("github.com")),
+                ("/keys/id_gitlab", Some("gitlab.com"))
+            ]
+        );
+        assert_eq!(work.sshkey_path.as_deref(), Some(Path::new("/keys/legacy")));
+        assert_eq!(users.get("home").unwrap().primary_key(), None);
+    }
+
+    #[test]
+    fn primary_key_edits_go_to_the_first_key_spec() {
+        let mut user = User {
+            sshkey_path: Some(PathBuf::from("/keys/legacy")),
+            keys: vec![KeySpec {
+                path: PathBuf::from("/keys/id_github"),
+                host: None,
+            }],
+            ..Default::default()
+        };
+        user.set_primary_key(PathBuf::from("/keys/id_new"));
+        assert_eq!(user.primary_key(), Some(Path::new("/keys/id_new")));
+        assert_eq!(user.sshkey_path.as_deref(), Some(Path::new("/keys/legacy")));
+
+        user.relocate_sshkey_paths(Path::new("/keys"), Path::new("/moved"));
+        assert_eq!(user.primary_key(), Some(Path::new("/moved/id_new")));
+        assert_eq!(
+            user.sshkey_path.as_deref(),
+            Some(Path::new("/moved/legacy"))
+        );
+
+        user.keys.clear();
+        user.set_primary_key(PathBuf::from("/keys/id_only"));
+        assert_eq!(
+            user.sshkey_path.as_deref(),
+            Some(Path::new("/keys/id_only"))
+        );
+    }
 }